@@ -3,27 +3,74 @@ extern crate jqsh;
 extern crate readline;
 extern crate unicode;
 
+use std::{env, io, process};
+use std::fs::File;
+use std::io::prelude::*;
+use std::os::raw::c_int;
+
 use eventual::Async;
 
 use unicode::UString;
 
 use jqsh::builtin;
-use jqsh::lang::{Filter, channel, parser};
+use jqsh::lang::{Context, Filter, Value, channel, parser};
 
 fn main() {
+    if let Some(path) = env::args().nth(1) {
+        // script mode: run the file once, and exit with a non-zero status if it raises an uncaught exception
+        let mut source_utf8 = String::new();
+        if let Err(err) = File::open(&path).and_then(|mut f| f.read_to_string(&mut source_utf8)) {
+            let _ = writeln!(io::stderr(), "jqsh: {}: {}", path, err);
+            process::exit(1);
+        }
+        let filter = parser::parse(UString::from(source_utf8), builtin::context()).unwrap_or_else(|err| {
+            let _ = writeln!(io::stderr(), "jqsh: syntax error: {}", err);
+            process::exit(1);
+        });
+        if let (_, false) = run(builtin::context(), &filter) {
+            process::exit(1);
+        }
+        return;
+    }
     let mut repl_context = builtin::context();
-    while let Some(source_utf8) = readline::readline("jqsh> ") {
+    loop {
+        let prompt = match repl_context.settings.get(&UString::from("prompt")) {
+            Some(&Value::String(ref prompt)) => String::from(prompt),
+            _ => "jqsh> ".to_owned()
+        };
+        let source_utf8 = if let Some(source_utf8) = readline::readline(&prompt) { source_utf8 } else { break; };
         readline::add_history(&source_utf8);
         let source = UString::from(source_utf8);
         let filter = parser::parse(source, repl_context.clone()).unwrap_or_else(|err| {
-            println!("jqsh: syntax error: {:?}", err);
+            let _ = writeln!(io::stderr(), "jqsh: syntax error: {}", err);
             Filter::Empty
         });
-        let channel::Receiver { context, values } = channel::Receiver::empty(repl_context).filter(&filter);
-        repl_context = context.await().expect("failed to get repl output context");
-        for value in values {
-            println!("{}", value);
-        }
+        repl_context = run(repl_context, &filter).0;
     }
     println!("");
 }
+
+extern "C" {
+    fn isatty(fd: c_int) -> c_int;
+}
+
+/// Runs the filter and prints its output, with an uncaught exception printed on stderr, in red if stderr is a terminal.
+///
+/// Returns the output context, and whether the filter ran without raising an exception.
+fn run(context: Context, filter: &Filter) -> (Context, bool) {
+    // like with `jq -n`, the filter is run on a single `null` input so that literals produce output
+    let channel::Receiver { context, values } = channel::Receiver::from_values(context, vec![Value::Null]).filter(filter);
+    let context = context.await().expect("failed to get output context");
+    for value in channel::Values::new(values) {
+        if let Value::Exception(_, _) = value {
+            if unsafe { isatty(2) } != 0 {
+                let _ = writeln!(io::stderr(), "\x1b[31m{}\x1b[0m", value);
+            } else {
+                let _ = writeln!(io::stderr(), "{}", value);
+            }
+            return (context, false);
+        }
+        println!("{}", value);
+    }
+    (context, true)
+}
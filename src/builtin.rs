@@ -1,18 +1,1855 @@
+use std::{env, iter};
+use std::collections::HashSet;
 use std::sync::Arc;
 
-use num::{FromPrimitive, BigRational};
+use num::{FromPrimitive, BigRational, One, Signed, ToPrimitive, Zero};
 
+use regex;
+
+use unicode::UString;
+
+use lang::Filter;
+use lang::channel::{Receiver, Sender, ValueSender};
 use lang::context::{Context, PrecedenceGroup};
+use lang::filter::for_each_value;
+use lang::parser;
+use lang::scope::Scope;
+use lang::value::{Array, Function, HashableValue, Object, Value};
+use lang::value::function::Param;
+use util::{FormatFn, Labeled, OperatorFn};
+
+/// Functions of the default context which are defined in jqsh itself, in terms of the native functions.
+const PRELUDE: &'static str = r#"
+def not: if . then false else true end;
+def error: raise "error" {message: .};
+def error($message): $message | error;
+def select(f): if f then . else empty end;
+def values: select(. != null);
+def map(f): [.[] | f];
+def map_values(f): .[] |= f;
+def in(xs): . as $x | xs | has($x);
+def inside(xs): . as $x | xs | contains($x);
+def index($i): indices($i) | .[0];
+def rindex($i): indices($i) | .[-1:][0];
+def any(generator; condition): first((generator | select(condition) | true), false);
+def all(generator; condition): first((generator | select(condition | not) | false), true);
+def any(condition): any(.[]; condition);
+def all(condition): all(.[]; condition);
+def any: any(.);
+def all: all(.);
+"#;
 
 /// The default context for interactive shell sessions.
 pub fn context() -> Context {
-    Context {
+    let mut context = Context {
         filter_allowed: Arc::new(Box::new(|_| true)),
         operators: vec![
             (1_000_000, PrecedenceGroup::Circumfix),
+            (900_000, PrecedenceGroup::Postfix),
+            (500_000, PrecedenceGroup::Infix(vec![
+                infix("*", multiply),
+                infix("/", divide),
+                infix("%", modulo)
+            ])),
+            (400_000, PrecedenceGroup::Infix(vec![
+                infix("+", add),
+                infix("-", subtract)
+            ])),
+            (300_000, PrecedenceGroup::Infix(vec![
+                infix("==", |lhs, rhs| Value::Boolean(lhs == rhs)),
+                infix("!=", |lhs, rhs| Value::Boolean(lhs != rhs)),
+                infix("<", |lhs, rhs| Value::Boolean(lhs < rhs)),
+                infix("<=", |lhs, rhs| Value::Boolean(lhs <= rhs)),
+                infix(">", |lhs, rhs| Value::Boolean(lhs > rhs)),
+                infix(">=", |lhs, rhs| Value::Boolean(lhs >= rhs))
+            ])),
+            (200_000, PrecedenceGroup::And),
+            (100_000, PrecedenceGroup::Or),
+            (-200_000, PrecedenceGroup::Assign(vec![
+                infix("+", add),
+                infix("-", subtract),
+                infix("*", multiply),
+                infix("/", divide),
+                infix("%", modulo),
+                infix("//", |lhs, rhs| if lhs.is_truthy() { lhs } else { rhs })
+            ])),
+            (-300_000, PrecedenceGroup::Alternative),
+            (-400_000, PrecedenceGroup::Comma),
+            (-500_000, PrecedenceGroup::Pipe),
             (-1_000_000, PrecedenceGroup::AndThen)
         ].into_iter().map(|(precedence, group)| {
             (BigRational::from_integer(FromPrimitive::from_i32(precedence).unwrap()), group)
-        }).collect()
+        }).collect(),
+        functions: vec![
+            native("add", &[], add_values),
+            native("ascii", &[], ascii),
+            native("ascii_downcase", &[], ascii_downcase),
+            native("ascii_upcase", &[], ascii_upcase),
+            native("capture", &["$re"], capture),
+            native("capture", &["$re", "$flags"], capture),
+            native("contains", &["$element"], contains),
+            native("del", &["f"], del),
+            native("delpaths", &["$paths"], delpaths),
+            path_native("empty", &[], empty, empty_paths),
+            native("endswith", &["$s"], endswith),
+            native("explode", &[], explode),
+            path_native("first", &["f"], first, first_paths),
+            native("flatten", &[], flatten),
+            native("flatten", &["$depth"], flatten),
+            native("from_entries", &[], from_entries),
+            native("fromjson", &[], fromjson),
+            path_native("getpath", &["$path"], getpath, getpath_paths),
+            native("group_by", &["f"], group_by),
+            native("gsub", &["$re", "str"], gsub),
+            native("gsub", &["$re", "str", "$flags"], gsub),
+            native("has", &["$key"], has),
+            native("implode", &[], implode),
+            native("indices", &["$i"], indices),
+            native("join", &["$sep"], join),
+            native("keys", &[], keys),
+            native("keys_unsorted", &[], keys_unsorted),
+            path_native("last", &["f"], last, last_paths),
+            native("leaf_paths", &[], leaf_paths),
+            native("length", &[], length),
+            path_native("limit", &["$n", "f"], limit, limit_paths),
+            native("ltrimstr", &["$s"], ltrimstr),
+            native("match", &["$re"], match_),
+            native("match", &["$re", "$flags"], match_),
+            native("max_by", &["f"], max_by),
+            native("min_by", &["f"], min_by),
+            native("path", &["f"], path),
+            native("paths", &[], paths),
+            native("paths", &["f"], paths),
+            native("range", &["$upto"], range),
+            native("range", &["$from", "$upto"], range),
+            native("range", &["$from", "$upto", "$by"], range),
+            path_native("recurse", &[], recurse, recurse_paths),
+            path_native("recurse", &["f"], recurse, recurse_paths),
+            path_native("recurse", &["f", "cond"], recurse, recurse_paths),
+            path_native("repeat", &["f"], recurse, recurse_paths), // same definition as `recurse(f)`
+            native("reverse", &[], reverse),
+            native("rtrimstr", &["$s"], rtrimstr),
+            native("scan", &["$re"], scan),
+            native("scan", &["$re", "$flags"], scan),
+            native("setpath", &["$path", "$value"], setpath),
+            native("sort", &[], sort),
+            native("sort_by", &["f"], sort),
+            native("split", &["$sep"], split_string),
+            native("split", &["$re", "$flags"], split),
+            native("splits", &["$re"], splits),
+            native("splits", &["$re", "$flags"], splits),
+            native("startswith", &["$s"], startswith),
+            native("sub", &["$re", "str"], sub),
+            native("sub", &["$re", "str", "$flags"], sub),
+            native("test", &["$re"], test),
+            native("test", &["$re", "$flags"], test),
+            native("to_entries", &[], to_entries),
+            native("tojson", &[], tojson),
+            native("tonumber", &[], tonumber),
+            native("tostring", &[], tostring),
+            native("type", &[], type_),
+            native("unique", &[], unique),
+            native("unique_by", &["f"], unique),
+            native("until", &["cond", "update"], until),
+            native("utf8bytelength", &[], utf8bytelength),
+            native("walk", &["f"], walk),
+            native("while", &["cond", "update"], while_),
+            native("with_entries", &["f"], with_entries)
+        ].into_iter().map(|function| ((function.name.clone(), function.arity()), function)).collect(),
+        local_functions: HashSet::new(),
+        formats: vec![
+            string_format("base64", base64),
+            string_format("base64d", base64d),
+            string_format("csv", csv),
+            string_format("html", html),
+            string_format("json", |value| Value::String(UString::from(value.to_json()))),
+            string_format("sh", sh),
+            string_format("text", |value| Value::String(UString::from(text(value)))),
+            string_format("tsv", tsv),
+            string_format("uri", uri)
+        ].into_iter().collect(),
+        variables: vec![
+            ("ENV", Value::Object(env::vars().map(|(k, v)| (HashableValue::String(UString::from(k)), Value::String(UString::from(v)))).collect()))
+        ].into_iter().map(|(name, value)| (UString::from(name), value)).collect(),
+        settings: vec![
+            ("prompt", Value::String(UString::from("jqsh> ")))
+        ].into_iter().map(|(name, value)| (UString::from(name), value)).collect()
+    };
+    // the prelude is bound one definition at a time, like when running it, so each function calls those defined before it even if they are redefined later
+    let mut prelude = parser::parse(PRELUDE, context.clone()).expect("failed to parse prelude");
+    let mut scope = Scope::default();
+    while let Filter::Def { function, rest, .. } = prelude.bind(&scope) {
+        scope.insert_function(function.clone());
+        context.functions.insert((function.name.clone(), function.arity()), function);
+        prelude = *rest;
+    }
+    context
+}
+
+fn infix(symbol: &str, f: fn(Value, Value) -> Value) -> OperatorFn {
+    Labeled::new(symbol, Arc::new(f))
+}
+
+fn string_format(name: &str, f: fn(Value) -> Value) -> (UString, FormatFn) {
+    (UString::from(name), Labeled::new(format!("@{}", name), Arc::new(f)))
+}
+
+/// A function implemented in Rust. Parameters starting with `$` are value parameters, the others are filter parameters.
+///
+/// The arguments are passed to `run` as attributes, in order. Like with `def`, the function runs once for each combination of the values of its value parameters, so each of those attributes produces exactly one value.
+fn native(name: &str, params: &[&str], run: fn(&[Filter], Receiver, Sender)) -> Function {
+    native_function(name, params, run, None)
+}
+
+/// Like `native`, but the function can also be used in path expressions like `path(first(.[]))`, using `paths` to run it in path mode.
+fn path_native(name: &str, params: &[&str], run: fn(&[Filter], Receiver, Sender), paths: NativePathsFn) -> Function {
+    native_function(name, params, run, Some(paths))
+}
+
+/// Runs a native function in path mode, like `Filter::run_paths`.
+type NativePathsFn = fn(&[Filter], &Context, Vec<Value>, Value, &mut FnMut(Vec<Value>, Value) -> bool) -> bool;
+
+fn native_function(name: &str, params: &[&str], run: fn(&[Filter], Receiver, Sender), paths: Option<NativePathsFn>) -> Function {
+    let params = params.iter().map(|&param| if param.starts_with('$') {
+        Param::Value(UString::from(&param[1..]))
+    } else {
+        Param::Filter(UString::from(param))
+    }).collect::<Vec<_>>();
+    Function {
+        name: UString::from(name),
+        body: Arc::new(Filter::Custom {
+            attributes: params.iter().map(|param| Filter::Call { name: param.name().clone(), args: vec![] }).collect(),
+            run: Box::new(Labeled::new(name, Arc::new(run))),
+            paths: paths.map(|paths| Box::new(Labeled::new(name, Arc::new(paths) as Arc<Fn(&[Filter], &Context, Vec<Value>, Value, &mut FnMut(Vec<Value>, Value) -> bool) -> bool + Send + Sync>)))
+        }),
+        params: params
+    }
+}
+
+/// Returns the value of an attribute which is a value parameter of a native function.
+fn value_arg(arg: &Filter, context: &Context, input: &Value) -> Value {
+    arg.run_value(context.clone(), input.clone()).into_iter().next().unwrap_or(Value::Null)
+}
+
+/// Returns the value of an attribute which is a value parameter used as a path, like the `$path` in `getpath($path)`.
+fn path_arg(arg: &Filter, context: &Context, input: &Value) -> Result<Vec<Value>, Value> {
+    match value_arg(arg, context, input) {
+        Value::Array(path) => Ok(path.into_iter().collect()),
+        Value::Exception(name, meta) => Err(Value::Exception(name, meta)),
+        path => Err(Value::type_error(format!("a path must be an array, not {}", path.type_name())))
+    }
+}
+
+/// Returns `path` with `component` added to the end.
+fn child_path(path: &[Value], component: Value) -> Vec<Value> {
+    path.iter().cloned().chain(iter::once(component)).collect()
+}
+
+/// `first(f)`: the first output of `f`. The rest of `f` is not evaluated.
+fn first(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        if let Some(first) = args[0].run_value(ctxt.clone(), value).into_iter().next() {
+            output.send(first);
+        }
+    });
+}
+
+fn first_paths(args: &[Filter], context: &Context, path: Vec<Value>, value: Value, output: &mut FnMut(Vec<Value>, Value) -> bool) -> bool {
+    let mut first = None;
+    args[0].run_paths(context, path, value, &mut |path, value| {
+        first = Some((path, value));
+        false
+    });
+    if let Some((path, value)) = first { output(path, value) } else { true }
+}
+
+/// `last(f)`: the last output of `f`.
+fn last(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        if let Some(last) = args[0].run_value(ctxt.clone(), value).into_iter().last() {
+            output.send(last);
+        }
+    });
+}
+
+fn last_paths(args: &[Filter], context: &Context, path: Vec<Value>, value: Value, output: &mut FnMut(Vec<Value>, Value) -> bool) -> bool {
+    let mut last = None;
+    args[0].run_paths(context, path, value, &mut |path, value| {
+        let is_exception = if let Value::Exception(_, _) = value { true } else { false };
+        last = Some((path, value));
+        !is_exception
+    });
+    if let Some((path, value)) = last { output(path, value) } else { true }
+}
+
+/// Converts the `$n` argument of `limit` into a number of outputs.
+fn limit_count(n: Value) -> Result<usize, Value> {
+    match n {
+        Value::Exception(_, _) => Err(n),
+        Value::Number(n) => Ok(if n.is_positive() { n.ceil().to_integer().to_usize().unwrap_or(usize::max_value()) } else { 0 }),
+        n => Err(Value::type_error(format!("limit must be a number, not {}", n.type_name())))
+    }
+}
+
+/// `limit($n; f)`: the first `$n` outputs of `f`, or none if `$n` is not positive. The rest of `f` is not evaluated.
+fn limit(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let n = match limit_count(value_arg(&args[0], ctxt, &value)) {
+            Ok(n) => n,
+            Err(exception) => {
+                output.send(exception);
+                return;
+            }
+        };
+        if n == 0 { return; }
+        for limited in args[1].run_value(ctxt.clone(), value).into_iter().take(n) {
+            if !output.send(limited) { return; }
+        }
+    });
+}
+
+fn limit_paths(args: &[Filter], context: &Context, path: Vec<Value>, value: Value, output: &mut FnMut(Vec<Value>, Value) -> bool) -> bool {
+    let n = match limit_count(value_arg(&args[0], context, &value)) {
+        Ok(n) => n,
+        Err(exception) => { return output(vec![], exception); }
+    };
+    if n == 0 { return true; }
+    let mut count = 0;
+    let mut stopped = false;
+    args[1].run_paths(context, path, value, &mut |path, value| {
+        count += 1;
+        if !output(path, value) {
+            stopped = true;
+            return false;
+        }
+        count < n
+    });
+    !stopped
+}
+
+/// `getpath($path)`: the value at the path, or `null` if it does not exist.
+fn getpath(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match path_arg(&args[0], ctxt, &value) {
+            Ok(path) => value.getpath(&path),
+            Err(exception) => exception
+        });
+    });
+}
+
+fn getpath_paths(args: &[Filter], context: &Context, path: Vec<Value>, value: Value, output: &mut FnMut(Vec<Value>, Value) -> bool) -> bool {
+    match path_arg(&args[0], context, &value) {
+        Ok(relative_path) => {
+            match value.getpath(&relative_path) {
+                Value::Exception(name, meta) => output(vec![], Value::Exception(name, meta)),
+                result => output(path.into_iter().chain(relative_path).collect(), result)
+            }
+        }
+        Err(exception) => output(vec![], exception)
+    }
+}
+
+/// `setpath($path; $value)`: a copy of the input with the value at the path replaced.
+fn setpath(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match path_arg(&args[0], ctxt, &value) {
+            Ok(path) => {
+                let new_value = value_arg(&args[1], ctxt, &value);
+                value.setpath(&path, new_value)
+            }
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `delpaths($paths)`: a copy of the input with the values at all of the paths removed.
+fn delpaths(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let paths = match value_arg(&args[0], ctxt, &value) {
+            Value::Array(paths) => paths,
+            Value::Exception(name, meta) => {
+                output.send(Value::Exception(name, meta));
+                return;
+            }
+            paths => {
+                output.send(Value::type_error(format!("paths must be an array, not {}", paths.type_name())));
+                return;
+            }
+        };
+        let mut path_vecs = vec![];
+        for path in paths {
+            if let Value::Array(path) = path {
+                path_vecs.push(path.into_iter().collect());
+            } else {
+                output.send(Value::type_error(format!("a path must be an array, not {}", path.type_name())));
+                return;
+            }
+        }
+        output.send(value.delpaths(path_vecs));
+    });
+}
+
+/// `del(f)`: a copy of the input with the values at all paths of `f` removed.
+fn del(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match args[0].paths(ctxt, &value) {
+            Ok(paths) => value.delpaths(paths),
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `path(f)`: the paths of the outputs of `f` within the input, as arrays of keys, indices, and slices.
+fn path(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        args[0].run_paths(ctxt, vec![], value, &mut |path, value| {
+            if let Value::Exception(_, _) = value {
+                output.send(value);
+                return false;
+            }
+            output.send(Value::Array(Array::from(path)))
+        });
+    });
+}
+
+/// `leaf_paths`: the paths of all values within the input which are not arrays or objects.
+fn leaf_paths(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        recurse_paths(&[], ctxt, vec![], value, &mut |path, value| {
+            match value {
+                Value::Array(_) | Value::Object(_) => true,
+                _ => output.send(Value::Array(Array::from(path)))
+            }
+        });
+    });
+}
+
+/// `paths` and `paths(f)`: the paths of all values within the input, except for the input itself. With `f`, only paths to values for which `f` is true are included.
+fn paths(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        recurse_paths(&[], ctxt, vec![], value, &mut |path, value| {
+            if path.is_empty() { return true; }
+            if let Some(f) = args.get(0) {
+                for f_value in f.run_value(ctxt.clone(), value.clone()) {
+                    if let Value::Exception(_, _) = f_value {
+                        output.send(f_value);
+                        return false;
+                    }
+                    if f_value.is_truthy() && !output.send(Value::Array(Array::from(path.clone()))) { return false; }
+                }
+                true
+            } else {
+                output.send(Value::Array(Array::from(path)))
+            }
+        });
+    });
+}
+
+/// The entries of an object or array, as `{"key": k, "value": v}` objects.
+fn entries(value: Value) -> Result<Vec<Value>, Value> {
+    let entry = |key, value| Value::Object(vec![
+        (HashableValue::String(UString::from("key")), key),
+        (HashableValue::String(UString::from("value")), value)
+    ].into_iter().collect());
+    match value {
+        Value::Array(array) => Ok(array.into_iter().enumerate().map(|(idx, item)| {
+            entry(usize_number(idx), item)
+        }).collect()),
+        Value::Exception(name, meta) => Err(Value::Exception(name, meta)),
+        Value::Object(object) => Ok(object.into_iter().map(|(key, item)| entry(Value::from(key), item)).collect()),
+        value => Err(Value::type_error(format!("cannot get entries of {}", value.type_name())))
+    }
+}
+
+/// Builds an object from entries like the ones returned by `entries`. Like in jq, the names `k`, `name`, `Name`, `K`, and `Key` are also accepted for the key, and `v` for the value.
+fn object_from_entries<I: IntoIterator<Item = Value>>(entries: I) -> Value {
+    let mut result = Object::default();
+    for entry in entries {
+        let entry = match entry {
+            Value::Object(entry) => entry,
+            Value::Exception(name, meta) => { return Value::Exception(name, meta); }
+            entry => { return Value::type_error(format!("an entry must be an object, not {}", entry.type_name())); }
+        };
+        let field = |name: &str| entry.get(&HashableValue::String(UString::from(name))).cloned();
+        let key = ["key", "k", "name", "Name", "K", "Key"].iter()
+            .filter_map(|&name| field(name))
+            .find(Value::is_truthy)
+            .unwrap_or(Value::Null);
+        let key = match key {
+            Value::String(key) => key,
+            key @ Value::Null | key @ Value::Boolean(_) | key @ Value::Number(_) => UString::from(key.to_json()),
+            key => { return Value::type_error(format!("an object key must be a string, not {}", key.type_name())); }
+        };
+        let value = field("value").or_else(|| field("v")).unwrap_or(Value::Null);
+        result.insert(HashableValue::String(key), value);
+    }
+    Value::Object(result)
+}
+
+/// `to_entries`: the entries of an object or array, as `{"key": k, "value": v}` objects.
+fn to_entries(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match entries(value) {
+            Ok(entries) => Value::Array(Array::from(entries)),
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `from_entries`: the inverse of `to_entries`.
+fn from_entries(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match value {
+            Value::Array(entries) => object_from_entries(entries),
+            Value::Exception(name, meta) => Value::Exception(name, meta),
+            value => Value::type_error(format!("cannot build an object from {}", value.type_name()))
+        });
+    });
+}
+
+/// `with_entries(f)`: `to_entries`, then `f` applied to each entry, then `from_entries`.
+fn with_entries(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match entries(value) {
+            Ok(entries) => object_from_entries(entries.into_iter().flat_map(|entry| args[0].run_value(ctxt.clone(), entry).into_iter())),
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `range($upto)`, `range($from; $upto)`, and `range($from; $upto; $by)`: the numbers from `$from` (default 0) up to but excluding `$upto`, in steps of `$by` (default 1).
+///
+/// With a negative step, the numbers count down to `$upto` instead. With a step of 0, there is no output.
+fn range(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let mut bounds = args.iter().map(|arg| value_arg(arg, ctxt, &value)).collect::<Vec<_>>();
+        if bounds.len() == 1 {
+            bounds.insert(0, Value::Number(BigRational::zero()));
+        }
+        if bounds.len() == 2 {
+            bounds.push(Value::Number(BigRational::one()));
+        }
+        let mut numbers = vec![];
+        for bound in bounds {
+            match bound {
+                Value::Number(n) => { numbers.push(n); }
+                Value::Exception(name, meta) => {
+                    output.send(Value::Exception(name, meta));
+                    return;
+                }
+                bound => {
+                    output.send(Value::type_error(format!("range bounds must be numbers, not {}", bound.type_name())));
+                    return;
+                }
+            }
+        }
+        let by = numbers.pop().unwrap();
+        let upto = numbers.pop().unwrap();
+        let mut n = numbers.pop().unwrap();
+        while (by.is_positive() && n < upto) || (by.is_negative() && n > upto) {
+            if !output.send(Value::Number(n.clone())) { return; }
+            n = n + &by;
+        }
+    });
+}
+
+/// A step of a recursive generator: either a value to output, or a value to continue the recursion from.
+enum Step {
+    Output(Value),
+    Visit(Value)
+}
+
+/// Runs a recursive generator, starting with the steps in `root`, using an explicit stack so that deep recursion does not overflow.
+///
+/// `visit` returns the steps for a value. They are evaluated depth-first, and any exception ends the output.
+fn recursive_steps<'a, V>(root: Box<Iterator<Item = Step> + 'a>, output: &ValueSender, visit: V) where V: Fn(Value) -> Box<Iterator<Item = Step> + 'a> {
+    let mut stack = vec![root];
+    loop {
+        let step = match stack.last_mut() {
+            Some(steps) => steps.next(),
+            None => { return; }
+        };
+        match step {
+            Some(Step::Output(value)) | Some(Step::Visit(value @ Value::Exception(_, _))) => {
+                let is_exception = if let Value::Exception(_, _) = value { true } else { false };
+                if !output.send(value) || is_exception { return; }
+            }
+            Some(Step::Visit(value)) => { stack.push(visit(value)); }
+            None => { stack.pop(); }
+        }
+    }
+}
+
+/// Returns the outputs of `cond` as steps, replacing each truthy output with `step(value)`. Falsy outputs are replaced with the result of `otherwise`.
+fn condition_steps<'a, S, O>(cond: &'a Filter, context: &'a Context, value: Value, step: S, otherwise: O) -> Box<Iterator<Item = Step> + 'a>
+where S: Fn(Value) -> Step + 'a, O: Fn(Value) -> Box<Iterator<Item = Step> + 'a> + 'a {
+    Box::new(cond.run_value(context.clone(), value.clone()).into_iter().flat_map(move |cond_value| -> Box<Iterator<Item = Step> + 'a> {
+        match cond_value {
+            Value::Exception(_, _) => Box::new(iter::once(Step::Output(cond_value))),
+            ref cond_value if cond_value.is_truthy() => Box::new(iter::once(step(value.clone()))),
+            _ => otherwise(value.clone())
+        }
+    }))
+}
+
+fn recurse_paths(args: &[Filter], context: &Context, path: Vec<Value>, value: Value, output: &mut FnMut(Vec<Value>, Value) -> bool) -> bool {
+    if is_leaf(args, &value) { return output(path, value); }
+    if !output(path.clone(), value.clone()) { return false; }
+    match (args.get(0), args.get(1)) {
+        (Some(f), cond) => f.run_paths(context, path, value, &mut |child_path, child| {
+            if let Value::Exception(_, _) = child {
+                return output(vec![], child);
+            }
+            if let Some(cond) = cond {
+                for cond_value in cond.run_value(context.clone(), child.clone()) {
+                    if let Value::Exception(_, _) = cond_value {
+                        return output(vec![], cond_value);
+                    }
+                    if cond_value.is_truthy() && !recurse_paths(args, context, child_path.clone(), child.clone(), output) { return false; }
+                }
+                true
+            } else {
+                recurse_paths(args, context, child_path, child, output)
+            }
+        }),
+        (None, _) => match value {
+            Value::Array(array) => {
+                for (idx, item) in array.into_iter().enumerate() {
+                    let idx = usize_number(idx);
+                    if !recurse_paths(args, context, child_path(&path, idx), item, output) { return false; }
+                }
+                true
+            }
+            Value::Object(object) => {
+                for (key, item) in object {
+                    if !recurse_paths(args, context, child_path(&path, Value::from(key)), item, output) { return false; }
+                }
+                true
+            }
+            _ => true
+        }
+    }
+}
+
+/// `recurse`, `recurse(f)`, and `recurse(f; cond)`: the input, followed by the recursive outputs of `f` on it, depth-first.
+///
+/// Without `f`, recurses into the values of arrays and objects. With `cond`, only outputs of `f` for which `cond` is true are included, along with their descendants.
+fn recurse(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let children = |value: Value| -> Box<Iterator<Item = Step>> {
+            match (args.get(0), args.get(1)) {
+                (Some(f), Some(cond)) => Box::new(f.run_value(ctxt.clone(), value).into_iter().flat_map(move |child| -> Box<Iterator<Item = Step>> {
+                    if let Value::Exception(_, _) = child {
+                        Box::new(iter::once(Step::Output(child)))
+                    } else {
+                        condition_steps(cond, ctxt, child, Step::Visit, |_| Box::new(iter::empty()))
+                    }
+                })),
+                (Some(f), None) => Box::new(f.run_value(ctxt.clone(), value).into_iter().map(Step::Visit)),
+                (None, _) => match value {
+                    Value::Array(array) => Box::new(array.into_iter().map(Step::Visit)),
+                    Value::Object(object) => Box::new(object.into_iter().map(|(_, child)| Step::Visit(child))),
+                    _ => Box::new(iter::empty())
+                }
+            }
+        };
+        recursive_steps(Box::new(iter::once(Step::Visit(value))), output, |value| -> Box<Iterator<Item = Step>> {
+            if is_leaf(args, &value) {
+                Box::new(iter::once(Step::Output(value)))
+            } else {
+                // the children are moved out of the node, and only once its copy has been output
+                Box::new(iter::once(Step::Output(value.clone())).chain(iter::once(value).flat_map(&children)))
+            }
+        });
+    });
+}
+
+/// Whether `recurse` without arguments would output the value without recursing into it. Such values are moved into the output instead of being copied.
+fn is_leaf(args: &[Filter], value: &Value) -> bool {
+    args.is_empty() && match *value {
+        Value::Array(_) | Value::Object(_) => false,
+        _ => true
+    }
+}
+
+/// `until(cond; update)`: applies `update` repeatedly until `cond` is true, and outputs the result.
+fn until(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let (cond, update) = (&args[0], &args[1]);
+        let visit = |value: Value| condition_steps(cond, ctxt, value, Step::Output, |value| Box::new(update.run_value(ctxt.clone(), value).into_iter().map(Step::Visit)));
+        recursive_steps(Box::new(iter::once(Step::Visit(value))), output, visit);
+    });
+}
+
+/// Applies `walk(f)` to the children of `value`, and returns it with each child replaced by the results.
+///
+/// Like in jq, an array item is replaced with all of the results, and an object value with the first result, or removed if there are none.
+fn walk_children(f: &Filter, context: &Context, value: Value) -> Result<Value, Value> {
+    match value {
+        Value::Array(array) => {
+            let mut result = vec![];
+            for item in array {
+                result.extend(try!(walk_value(f, context, item)));
+            }
+            Ok(Value::Array(Array::from(result)))
+        }
+        Value::Object(object) => {
+            let mut result = Object::default();
+            for (key, item) in object {
+                if let Some(item) = try!(walk_value(f, context, item)).into_iter().next() {
+                    result.insert(key, item);
+                }
+            }
+            Ok(Value::Object(result))
+        }
+        value => Ok(value)
+    }
+}
+
+/// All outputs of `walk(f)` on a value which is not the input, or the first exception.
+fn walk_value(f: &Filter, context: &Context, value: Value) -> Result<Vec<Value>, Value> {
+    let mut outputs = vec![];
+    for output in f.run_value(context.clone(), try!(walk_children(f, context, value))) {
+        if let Value::Exception(_, _) = output { return Err(output); }
+        outputs.push(output);
+    }
+    Ok(outputs)
+}
+
+/// `walk(f)`: applies `f` to every value within the input, bottom-up, so `f` sees each array or object after its children have been replaced.
+fn walk(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        match walk_children(&args[0], ctxt, value) {
+            Ok(value) => {
+                for walked in args[0].run_value(ctxt.clone(), value) {
+                    if !output.send(walked) { return; }
+                }
+            }
+            Err(exception) => { output.send(exception); }
+        }
+    });
+}
+
+/// `while(cond; update)`: the input and the results of applying `update` repeatedly, for as long as `cond` is true.
+fn while_(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let (cond, update) = (&args[0], &args[1]);
+        let visit = |value: Value| -> Box<Iterator<Item = Step>> {
+            let children = update.run_value(ctxt.clone(), value.clone()).into_iter().flat_map(move |child| -> Box<Iterator<Item = Step>> {
+                if let Value::Exception(_, _) = child {
+                    Box::new(iter::once(Step::Output(child)))
+                } else {
+                    condition_steps(cond, ctxt, child, Step::Visit, |_| Box::new(iter::empty()))
+                }
+            });
+            Box::new(iter::once(Step::Output(value)).chain(children))
+        };
+        let root = condition_steps(cond, ctxt, value, Step::Visit, |_| Box::new(iter::empty()));
+        recursive_steps(root, output, visit);
+    });
+}
+
+/// Converts a length or index to a number value.
+fn usize_number(n: usize) -> Value {
+    Value::Number(BigRational::from_integer(FromPrimitive::from_usize(n).unwrap()))
+}
+
+/// `empty`: no output.
+fn empty(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, _, _| {});
+}
+
+fn empty_paths(_: &[Filter], _: &Context, _: Vec<Value>, _: Value, _: &mut FnMut(Vec<Value>, Value) -> bool) -> bool {
+    true
+}
+
+/// `type`: the name of the type of the input, like `"number"`.
+fn type_(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(Value::String(UString::from(value.type_name())));
+    });
+}
+
+/// `length`: the number of code points in a string, items in an array, or entries in an object, the absolute value of a number, or 0 for `null`.
+fn length(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match value {
+            Value::Null => usize_number(0),
+            Value::Number(n) => Value::Number(n.abs()),
+            Value::String(s) => usize_number(s.len()),
+            Value::Array(array) => usize_number(array.len()),
+            Value::Object(object) => usize_number(object.len()),
+            value => Value::type_error(format!("{} has no length", value.type_name()))
+        });
+    });
+}
+
+/// `utf8bytelength`: the number of bytes in the UTF-8 encoding of the input string.
+fn utf8bytelength(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match value {
+            Value::String(s) => usize_number(String::from(s).len()),
+            value => Value::type_error(format!("{} has no UTF-8 byte length, only a string", value.type_name()))
+        });
+    });
+}
+
+/// The keys of an object in insertion order, or the indices of an array.
+fn unsorted_keys(value: Value) -> Result<Vec<Value>, Value> {
+    match value {
+        Value::Array(array) => Ok((0..array.len()).map(usize_number).collect()),
+        Value::Object(object) => Ok(object.into_iter().map(|(key, _)| Value::from(key)).collect()),
+        value => Err(Value::type_error(format!("{} has no keys", value.type_name())))
+    }
+}
+
+/// `keys`: the keys of the input object in sorted order, or the indices of the input array.
+fn keys(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match unsorted_keys(value) {
+            Ok(mut keys) => {
+                keys.sort();
+                Value::Array(Array::from(keys))
+            }
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `keys_unsorted`: like `keys`, but the keys of an object are in insertion order.
+fn keys_unsorted(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match unsorted_keys(value) {
+            Ok(keys) => Value::Array(Array::from(keys)),
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `has($key)`: whether the input object has the key, or whether the index is in bounds of the input array.
+fn has(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match (value_arg(&args[0], ctxt, &value), value) {
+            (Value::Exception(name, meta), _) => Value::Exception(name, meta),
+            (Value::String(key), Value::Object(object)) => Value::Boolean(object.get(&HashableValue::String(key)).is_some()),
+            (Value::Number(idx), Value::Array(array)) => Value::Boolean(!idx.is_negative() && idx.floor().to_integer().to_usize().map_or(false, |idx| idx < array.len())),
+            (key, value) => Value::type_error(format!("cannot check whether {} has a {} key", value.type_name(), key.type_name()))
+        });
+    });
+}
+
+/// `add`: the values of the input array or object combined using `+`, or `null` if there are none.
+fn add_values(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        let values = match value {
+            Value::Array(array) => array.into_iter().collect::<Vec<_>>(),
+            Value::Object(object) => object.into_iter().map(|(_, value)| value).collect(),
+            value => {
+                output.send(Value::type_error(format!("cannot add the values of {}", value.type_name())));
+                return;
+            }
+        };
+        let mut sum = Value::Null;
+        for value in values {
+            sum = add(sum, value);
+            if let Value::Exception(_, _) = sum { break; }
+        }
+        output.send(sum);
+    });
+}
+
+/// Appends the items of `array` to `result`, replacing nested arrays with their items up to `depth` levels deep.
+fn flatten_into(array: Array<Value>, depth: usize, result: &mut Vec<Value>) {
+    for item in array {
+        match item {
+            Value::Array(inner) if depth > 0 => { flatten_into(inner, depth - 1, result); }
+            item => { result.push(item); }
+        }
+    }
+}
+
+/// `flatten` and `flatten($depth)`: the input array with nested arrays replaced by their items, up to `$depth` levels deep.
+fn flatten(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let depth = match args.get(0).map(|depth| value_arg(depth, ctxt, &value)) {
+            None => usize::max_value(),
+            Some(Value::Number(depth)) => if depth.is_negative() {
+                output.send(Value::type_error("flatten depth must not be negative"));
+                return;
+            } else {
+                depth.floor().to_integer().to_usize().unwrap_or(usize::max_value())
+            },
+            Some(Value::Exception(name, meta)) => {
+                output.send(Value::Exception(name, meta));
+                return;
+            }
+            Some(depth) => {
+                output.send(Value::type_error(format!("flatten depth must be a number, not {}", depth.type_name())));
+                return;
+            }
+        };
+        output.send(match value {
+            Value::Array(array) => {
+                let mut result = vec![];
+                flatten_into(array, depth, &mut result);
+                Value::Array(Array::from(result))
+            }
+            value => Value::type_error(format!("cannot flatten {}, only an array", value.type_name()))
+        });
+    });
+}
+
+/// `tostring`: the input string, or any other value as JSON.
+fn tostring(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(Value::String(UString::from(text(value))));
+    });
+}
+
+/// `tojson`: the input as JSON.
+fn tojson(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(Value::String(UString::from(value.to_json())));
+    });
+}
+
+/// `fromjson`: the value encoded as JSON in the input string.
+///
+/// The string is parsed as code in which only literals, arrays, and objects are allowed.
+fn fromjson(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let json = match value {
+            Value::String(json) => json,
+            value => {
+                output.send(Value::type_error(format!("cannot parse {} as JSON, only a string", value.type_name())));
+                return;
+            }
+        };
+        let json_context = Context {
+            filter_allowed: Arc::new(Box::new(|f| match *f {
+                Filter::Array(_) | Filter::Comma { .. } | Filter::Constant(_) | Filter::Object(_) => true,
+                _ => false
+            })),
+            ..ctxt.clone()
+        };
+        let invalid = |json: &UString| Value::exception("json", vec![("message", Value::String(UString::from(format!("{:?} is not valid JSON", String::from(json)))))]);
+        let mut values = match parser::parse(json.clone(), json_context.clone()) {
+            Ok(f) => f.run_value(json_context, Value::Null).into_iter().collect::<Vec<_>>(),
+            Err(_) => vec![]
+        };
+        output.send(match (values.pop(), values.is_empty()) {
+            (Some(Value::Exception(_, _)), _) | (Some(_), false) | (None, _) => invalid(&json),
+            (Some(value), true) => value
+        });
+    });
+}
+
+/// `tonumber`: the input number, or the number in the input string.
+fn tonumber(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match value {
+            Value::Number(n) => Value::Number(n),
+            Value::String(s) => {
+                let s = String::from(s);
+                match parser::parse_number(&s) {
+                    Some(n) => Value::Number(n),
+                    None => Value::type_error(format!("cannot parse {:?} as a number", s))
+                }
+            }
+            value => Value::type_error(format!("cannot parse {} as a number", value.type_name()))
+        });
+    });
+}
+
+/// `ascii`: the character with the input code point, which must be between 0 and 127.
+fn ascii(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match value {
+            Value::Number(ref n) if n.is_integer() && !n.is_negative() && n.to_integer().to_u8().map_or(false, |b| b < 128) => {
+                Value::String(UString::from((n.to_integer().to_u8().unwrap() as char).to_string()))
+            }
+            value => Value::type_error(format!("{} is not an ASCII code point", value))
+        });
+    });
+}
+
+/// `explode`: the code points of the input string, as numbers.
+fn explode(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match value {
+            Value::String(s) => Value::Array(s.into_iter().map(|c| usize_number(c as usize)).collect::<Vec<_>>().into()),
+            value => Value::type_error(format!("cannot explode {}, only a string", value.type_name()))
+        });
+    });
+}
+
+/// `implode`: the inverse of `explode`.
+fn implode(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        let code_points = match value {
+            Value::Array(code_points) => code_points,
+            value => {
+                output.send(Value::type_error(format!("cannot implode {}, only an array", value.type_name())));
+                return;
+            }
+        };
+        let mut result = String::new();
+        for code_point in code_points {
+            match code_point {
+                Value::Number(ref n) if n.is_integer() && n.to_integer().to_u32().and_then(char::from_u32).is_some() => {
+                    result.push(n.to_integer().to_u32().and_then(char::from_u32).unwrap());
+                }
+                code_point => {
+                    output.send(Value::type_error(format!("{} is not a valid code point", code_point)));
+                    return;
+                }
+            }
+        }
+        output.send(Value::String(UString::from(result)));
+    });
+}
+
+/// `join($sep)`: the items of the input array as strings, separated by `$sep`. `null` becomes the empty string, and numbers and booleans are converted to JSON.
+fn join(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let sep = match value_arg(&args[0], ctxt, &value) {
+            Value::String(sep) => String::from(sep),
+            Value::Exception(name, meta) => {
+                output.send(Value::Exception(name, meta));
+                return;
+            }
+            sep => {
+                output.send(Value::type_error(format!("cannot join with {}, only a string", sep.type_name())));
+                return;
+            }
+        };
+        let items = match value {
+            Value::Array(items) => items,
+            value => {
+                output.send(Value::type_error(format!("cannot join {}, only an array", value.type_name())));
+                return;
+            }
+        };
+        let mut parts = vec![];
+        for item in items {
+            parts.push(match item {
+                Value::Null => String::new(),
+                Value::String(s) => String::from(s),
+                item @ Value::Boolean(_) | item @ Value::Number(_) => item.to_json(),
+                item => {
+                    output.send(Value::type_error(format!("cannot join {}", item.type_name())));
+                    return;
+                }
+            });
+        }
+        output.send(Value::String(UString::from(parts.join(&sep))));
+    });
+}
+
+/// `reverse`: the input array or string in reverse order. `null` becomes an empty array.
+fn reverse(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match value {
+            Value::Null => Value::Array(Array::new()),
+            Value::String(s) => Value::String(s.into_iter().collect::<Vec<_>>().into_iter().rev().collect()),
+            Value::Array(array) => Value::Array(array.into_iter().collect::<Vec<_>>().into_iter().rev().collect()),
+            value => Value::type_error(format!("cannot reverse {}", value.type_name()))
+        });
+    });
+}
+
+/// Pairs each item of the input array with the outputs of `f` on it as an array, which is the item's key for sorting and grouping.
+fn keyed_items(f: &Filter, context: &Context, value: Value) -> Result<Vec<(Value, Value)>, Value> {
+    let items = match value {
+        Value::Array(items) => items,
+        value => { return Err(Value::type_error(format!("cannot sort {}, only an array", value.type_name()))); }
+    };
+    let mut result = vec![];
+    for item in items {
+        let mut key = vec![];
+        for key_value in f.run_value(context.clone(), item.clone()) {
+            if let Value::Exception(_, _) = key_value { return Err(key_value); }
+            key.push(key_value);
+        }
+        result.push((Value::Array(Array::from(key)), item));
     }
+    Ok(result)
+}
+
+/// The items of the input array sorted by the outputs of `f`, or by the items themselves if there is no `f`, and grouped by equal keys.
+fn sorted_groups(args: &[Filter], context: &Context, value: Value) -> Result<Vec<Vec<Value>>, Value> {
+    let mut items = match args.get(0) {
+        Some(f) => try!(keyed_items(f, context, value)),
+        None => try!(keyed_items(&Filter::Identity, context, value))
+    };
+    items.sort_by(|&(ref l, _), &(ref r, _)| l.cmp(r)); // stable, so items with equal keys stay in order
+    let mut groups: Vec<(Value, Vec<Value>)> = vec![];
+    for (key, item) in items {
+        let is_new_group = groups.last().map_or(true, |&(ref group_key, _)| *group_key != key);
+        if is_new_group {
+            groups.push((key, vec![item]));
+        } else {
+            groups.last_mut().unwrap().1.push(item);
+        }
+    }
+    Ok(groups.into_iter().map(|(_, group)| group).collect())
+}
+
+/// `sort` and `sort_by(f)`: the items of the input array in sorted order, optionally comparing the outputs of `f` instead of the items.
+fn sort(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match sorted_groups(args, ctxt, value) {
+            Ok(groups) => Value::Array(groups.into_iter().flat_map(|group| group).collect::<Vec<_>>().into()),
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `group_by(f)`: arrays of the items of the input array for which `f` has the same outputs, sorted by those outputs.
+fn group_by(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match sorted_groups(args, ctxt, value) {
+            Ok(groups) => Value::Array(groups.into_iter().map(|group| Value::Array(Array::from(group))).collect::<Vec<_>>().into()),
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `unique` and `unique_by(f)`: the sorted items of the input array, keeping only the first item for each output of `f` or each distinct item.
+fn unique(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match sorted_groups(args, ctxt, value) {
+            Ok(groups) => Value::Array(groups.into_iter().filter_map(|group| group.into_iter().next()).collect::<Vec<_>>().into()),
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `min_by(f)`: the first item of the input array with the smallest outputs of `f`, or `null` if the array is empty.
+fn min_by(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match sorted_groups(args, ctxt, value) {
+            Ok(groups) => groups.into_iter().next().and_then(|group| group.into_iter().next()).unwrap_or(Value::Null),
+            Err(exception) => exception
+        });
+    });
+}
+
+/// `max_by(f)`: the last item of the input array with the largest outputs of `f`, or `null` if the array is empty.
+fn max_by(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match sorted_groups(args, ctxt, value) {
+            Ok(groups) => groups.into_iter().last().and_then(|group| group.into_iter().last()).unwrap_or(Value::Null),
+            Err(exception) => exception
+        });
+    });
+}
+
+/// Whether `lhs` contains `rhs`: objects contain objects whose values they contain at the same keys, arrays contain arrays whose items are each contained in one of their items, and strings contain their substrings. Other values only contain themselves.
+fn value_contains(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (&Value::Object(ref lhs), &Value::Object(ref rhs)) => rhs.iter().all(|(key, rhs_value)| lhs.get(key).map_or(false, |lhs_value| value_contains(lhs_value, rhs_value))),
+        (&Value::Array(ref lhs), &Value::Array(ref rhs)) => rhs.iter().all(|rhs_item| lhs.iter().any(|lhs_item| value_contains(lhs_item, rhs_item))),
+        (&Value::String(ref lhs), &Value::String(ref rhs)) => String::from(lhs).contains(&String::from(rhs)),
+        (lhs, rhs) => lhs == rhs
+    }
+}
+
+/// `contains($element)`: whether the input contains `$element`, as defined by `value_contains`. The input and `$element` must have the same type.
+fn contains(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match value_arg(&args[0], ctxt, &value) {
+            Value::Exception(name, meta) => Value::Exception(name, meta),
+            ref element if element.type_name() == value.type_name() => Value::Boolean(value_contains(&value, element)),
+            element => Value::type_error(format!("cannot check whether {} contains {}", value.type_name(), element.type_name()))
+        });
+    });
+}
+
+/// The start indices of the occurrences of `needle` in `haystack`, including overlapping ones.
+fn subsequence_indices<T: PartialEq>(haystack: &[T], needle: &[T]) -> Vec<Value> {
+    if needle.is_empty() || needle.len() > haystack.len() { return vec![]; }
+    (0..haystack.len() - needle.len() + 1).filter(|&start| haystack[start..start + needle.len()] == *needle).map(usize_number).collect()
+}
+
+/// `indices($i)`: the indices at which `$i` occurs in the input. For strings, these are code point offsets of the substring `$i`. For arrays, `$i` can be an array, which is searched for as a subarray, or an item.
+fn indices(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let needle = value_arg(&args[0], ctxt, &value);
+        output.send(match (value, needle) {
+            (_, Value::Exception(name, meta)) => Value::Exception(name, meta),
+            (Value::Null, _) => Value::Null,
+            (Value::String(haystack), Value::String(needle)) => {
+                let haystack = haystack.into_iter().collect::<Vec<_>>();
+                let needle = needle.into_iter().collect::<Vec<_>>();
+                Value::Array(Array::from(subsequence_indices(&haystack, &needle)))
+            }
+            (Value::Array(haystack), Value::Array(needle)) => {
+                let haystack = haystack.into_iter().collect::<Vec<_>>();
+                let needle = needle.into_iter().collect::<Vec<_>>();
+                Value::Array(Array::from(subsequence_indices(&haystack, &needle)))
+            }
+            (Value::Array(haystack), needle) => {
+                Value::Array(haystack.iter().enumerate().filter(|&(_, item)| *item == needle).map(|(idx, _)| usize_number(idx)).collect::<Vec<_>>().into())
+            }
+            (value, needle) => Value::type_error(format!("cannot search {} for {}", value.type_name(), needle.type_name()))
+        });
+    });
+}
+
+/// Calls `f` with the input string and the `$s` argument string for each input, as used by `startswith($s)` and similar functions.
+fn for_each_string_pair<F>(name: &'static str, args: &[Filter], input: Receiver, output: Sender, f: F) where F: Fn(String, String) -> Value {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match (value_arg(&args[0], ctxt, &value), value) {
+            (Value::Exception(name, meta), _) => Value::Exception(name, meta),
+            (Value::String(arg), Value::String(s)) => f(String::from(s), String::from(arg)),
+            (_, _) => Value::type_error(format!("{}() requires string inputs", name))
+        });
+    });
+}
+
+/// `startswith($s)`: whether the input string starts with `$s`.
+fn startswith(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_string_pair("startswith", args, input, output, |s, prefix| Value::Boolean(s.starts_with(&prefix)));
+}
+
+/// `endswith($s)`: whether the input string ends with `$s`.
+fn endswith(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_string_pair("endswith", args, input, output, |s, suffix| Value::Boolean(s.ends_with(&suffix)));
+}
+
+/// `split($sep)`: an array of the parts of the input string between occurrences of `$sep`. An empty separator splits the string into its characters.
+fn split_string(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_string_pair("split", args, input, output, |s, sep| {
+        let parts = if s.is_empty() {
+            vec![]
+        } else if sep.is_empty() {
+            s.chars().map(|c| Value::String(UString::from(c.to_string()))).collect()
+        } else {
+            s.split(&sep[..]).map(|part| Value::String(UString::from(part))).collect()
+        };
+        Value::Array(Array::from(parts))
+    });
+}
+
+/// `ltrimstr($s)`: the input with the prefix `$s` removed, if it is a string which starts with it. Otherwise, the input is unchanged.
+fn ltrimstr(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match (value_arg(&args[0], ctxt, &value), value) {
+            (Value::Exception(name, meta), _) => Value::Exception(name, meta),
+            (Value::String(prefix), Value::String(s)) => {
+                let (prefix, s) = (String::from(prefix), String::from(s));
+                Value::String(UString::from(if s.starts_with(&prefix) { &s[prefix.len()..] } else { &s[..] }))
+            }
+            (_, value) => value
+        });
+    });
+}
+
+/// `rtrimstr($s)`: the input with the suffix `$s` removed, if it is a string which ends with it. Otherwise, the input is unchanged.
+fn rtrimstr(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        output.send(match (value_arg(&args[0], ctxt, &value), value) {
+            (Value::Exception(name, meta), _) => Value::Exception(name, meta),
+            (Value::String(suffix), Value::String(s)) => {
+                let (suffix, s) = (String::from(suffix), String::from(s));
+                Value::String(UString::from(if s.ends_with(&suffix) { &s[..s.len() - suffix.len()] } else { &s[..] }))
+            }
+            (_, value) => value
+        });
+    });
+}
+
+/// `ascii_downcase`: the input string with ASCII letters converted to lowercase.
+fn ascii_downcase(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match value {
+            Value::String(s) => Value::String(UString::from(String::from(s).to_ascii_lowercase())),
+            value => Value::type_error(format!("cannot downcase {}, only a string", value.type_name()))
+        });
+    });
+}
+
+/// `ascii_upcase`: the input string with ASCII letters converted to uppercase.
+fn ascii_upcase(_: &[Filter], input: Receiver, output: Sender) {
+    for_each_value(input, output, |_, value, output| {
+        output.send(match value {
+            Value::String(s) => Value::String(UString::from(String::from(s).to_ascii_uppercase())),
+            value => Value::type_error(format!("cannot upcase {}, only a string", value.type_name()))
+        });
+    });
+}
+
+/// A regular expression with the flags which are not part of its syntax.
+struct Regex {
+    regex: regex::Regex,
+    /// `g`: all matches are used instead of only the first.
+    global: bool,
+    /// `n`: empty matches are ignored.
+    skip_empty: bool
+}
+
+/// A match of a regular expression. Positions are byte ranges in the matched string.
+struct RegexMatch {
+    start: usize,
+    end: usize,
+    /// The name of each capture group, and its position if it participated in the match.
+    captures: Vec<(Option<String>, Option<(usize, usize)>)>
+}
+
+/// The exception raised for an invalid regular expression or invalid flags.
+fn regex_error(message: String) -> Value {
+    Value::exception("regex", vec![("message", Value::String(UString::from(message)))])
+}
+
+/// The number of code points in `s` before `byte_offset`, which is how strings are indexed.
+fn char_offset(s: &str, byte_offset: usize) -> Value {
+    usize_number(s[..byte_offset].chars().count())
+}
+
+impl Regex {
+    /// Compiles the regular expression given by the `$re` and optional `$flags` arguments of a native function, with `extra_flags` added to the given flags.
+    ///
+    /// Like in jq, `$re` may also be an array of the regular expression and the flags.
+    fn from_args(re: &Filter, flags: Option<&Filter>, extra_flags: &str, context: &Context, input: &Value) -> Result<Regex, Value> {
+        let (re, flags) = match (value_arg(re, context, input), flags.map(|flags| value_arg(flags, context, input))) {
+            (Value::Array(re_flags), None) => {
+                let mut re_flags = re_flags.into_iter();
+                (re_flags.next().unwrap_or(Value::Null), re_flags.next())
+            }
+            (re, flags) => (re, flags)
+        };
+        let re = match re {
+            Value::String(re) => String::from(re),
+            Value::Exception(name, meta) => { return Err(Value::Exception(name, meta)); }
+            re => { return Err(Value::type_error(format!("a regular expression must be a string, not {}", re.type_name()))); }
+        };
+        let flags = match flags {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(flags)) => String::from(flags),
+            Some(Value::Exception(name, meta)) => { return Err(Value::Exception(name, meta)); }
+            Some(flags) => { return Err(Value::type_error(format!("regular expression flags must be a string, not {}", flags.type_name()))); }
+        };
+        let mut global = false;
+        let mut skip_empty = false;
+        let mut inline_flags = String::new();
+        for flag in flags.chars().chain(extra_flags.chars()) {
+            match flag {
+                'g' => { global = true; }
+                'n' => { skip_empty = true; }
+                'i' | 's' | 'x' => { inline_flags.push(flag); }
+                _ => { return Err(regex_error(format!("{:?} is not a valid modifier string", flags))); }
+            }
+        }
+        let re = if inline_flags.is_empty() { re } else { format!("(?{}){}", inline_flags, re) };
+        match regex::Regex::new(&re) {
+            Ok(regex) => Ok(Regex {
+                regex: regex,
+                global: global,
+                skip_empty: skip_empty
+            }),
+            Err(err) => Err(regex_error(err.to_string()))
+        }
+    }
+
+    /// The first match in `s`, or all of them if the `g` flag is set.
+    fn matches(&self, s: &str) -> Vec<RegexMatch> {
+        let names = self.regex.capture_names().skip(1).map(|name| name.map(|name| name.to_owned())).collect::<Vec<_>>();
+        let mut result = vec![];
+        for captures in self.regex.captures_iter(s) {
+            let whole = captures.get(0).unwrap();
+            if self.skip_empty && whole.start() == whole.end() { continue; }
+            result.push(RegexMatch {
+                start: whole.start(),
+                end: whole.end(),
+                captures: names.iter().enumerate().map(|(idx, name)| (name.clone(), captures.get(idx + 1).map(|group| (group.start(), group.end())))).collect()
+            });
+            if !self.global { break; }
+        }
+        result
+    }
+}
+
+impl RegexMatch {
+    /// The match as an object like the ones returned by `match`, with offsets and lengths in code points.
+    fn to_value(&self, s: &str) -> Value {
+        let span = |start: usize, end: usize| vec![
+            (HashableValue::String(UString::from("offset")), char_offset(s, start)),
+            (HashableValue::String(UString::from("length")), char_offset(&s[start..], end - start)),
+            (HashableValue::String(UString::from("string")), Value::String(UString::from(&s[start..end])))
+        ];
+        let captures = self.captures.iter().map(|&(ref name, group)| {
+            let mut capture = match group {
+                Some((start, end)) => span(start, end),
+                None => vec![
+                    (HashableValue::String(UString::from("offset")), Value::Number(-BigRational::one())),
+                    (HashableValue::String(UString::from("length")), Value::Number(BigRational::zero())),
+                    (HashableValue::String(UString::from("string")), Value::Null)
+                ]
+            };
+            capture.push((HashableValue::String(UString::from("name")), name.as_ref().map_or(Value::Null, |name| Value::String(UString::from(&name[..])))));
+            Value::Object(capture.into_iter().collect())
+        }).collect::<Vec<_>>();
+        let mut result = span(self.start, self.end);
+        result.push((HashableValue::String(UString::from("captures")), Value::Array(Array::from(captures))));
+        Value::Object(result.into_iter().collect())
+    }
+
+    /// The named captures of the match as an object, like the ones returned by `capture`.
+    fn capture_object(&self, s: &str) -> Value {
+        Value::Object(self.captures.iter().filter_map(|&(ref name, group)| name.as_ref().map(|name| {
+            (HashableValue::String(UString::from(&name[..])), group.map_or(Value::Null, |(start, end)| Value::String(UString::from(&s[start..end]))))
+        })).collect())
+    }
+}
+
+/// Calls `f` with each input string and its matches. `flags_idx` is the index of the `$flags` argument, and `extra_flags` are added to the given flags.
+fn for_each_match<F>(args: &[Filter], flags_idx: usize, extra_flags: &str, input: Receiver, output: Sender, mut f: F)
+where F: FnMut(&Context, &str, Vec<RegexMatch>, &ValueSender) {
+    for_each_value(input, output, |ctxt, value, output| {
+        let regex = match Regex::from_args(&args[0], args.get(flags_idx), extra_flags, ctxt, &value) {
+            Ok(regex) => regex,
+            Err(exception) => {
+                output.send(exception);
+                return;
+            }
+        };
+        match value {
+            Value::String(s) => {
+                let s = String::from(s);
+                let matches = regex.matches(&s);
+                f(ctxt, &s, matches, output);
+            }
+            value => { output.send(Value::type_error(format!("cannot match {}, only a string", value.type_name()))); }
+        }
+    });
+}
+
+/// `test($re)` and `test($re; $flags)`: whether the regular expression matches the input string.
+fn test(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_match(args, 1, "", input, output, |_, _, matches, output| {
+        output.send(Value::Boolean(!matches.is_empty()));
+    });
+}
+
+/// `match($re)` and `match($re; $flags)`: an object for each match of the regular expression in the input string.
+///
+/// The object has the `offset` and `length` of the match in code points, the matched `string`, and the `captures`, which are objects with the same keys and the `name` of the group.
+fn match_(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_match(args, 1, "", input, output, |_, s, matches, output| {
+        for regex_match in matches {
+            if !output.send(regex_match.to_value(s)) { return; }
+        }
+    });
+}
+
+/// `capture($re)` and `capture($re; $flags)`: an object of the named captures for each match.
+fn capture(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_match(args, 1, "", input, output, |_, s, matches, output| {
+        for regex_match in matches {
+            if !output.send(regex_match.capture_object(s)) { return; }
+        }
+    });
+}
+
+/// `scan($re)` and `scan($re; $flags)`: each match in the input string, or an array of its captured strings if the regular expression has capture groups.
+fn scan(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_match(args, 1, "g", input, output, |_, s, matches, output| {
+        for regex_match in matches {
+            let scanned = if regex_match.captures.is_empty() {
+                Value::String(UString::from(&s[regex_match.start..regex_match.end]))
+            } else {
+                Value::Array(regex_match.captures.iter().map(|&(_, group)| group.map_or(Value::Null, |(start, end)| Value::String(UString::from(&s[start..end])))).collect::<Vec<_>>().into())
+            };
+            if !output.send(scanned) { return; }
+        }
+    });
+}
+
+/// The parts of `s` between the matches.
+fn split_parts(s: &str, matches: &[RegexMatch]) -> Vec<Value> {
+    let mut parts = vec![];
+    let mut start = 0;
+    for regex_match in matches {
+        parts.push(Value::String(UString::from(&s[start..regex_match.start])));
+        start = regex_match.end;
+    }
+    parts.push(Value::String(UString::from(&s[start..])));
+    parts
+}
+
+/// `split($re; $flags)`: an array of the parts of the input string between the matches of the regular expression.
+fn split(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_match(args, 1, "g", input, output, |_, s, matches, output| {
+        output.send(Value::Array(Array::from(split_parts(s, &matches))));
+    });
+}
+
+/// `splits($re)` and `splits($re; $flags)`: like `split`, but outputs the parts instead of an array.
+fn splits(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_match(args, 1, "g", input, output, |_, s, matches, output| {
+        for part in split_parts(s, &matches) {
+            if !output.send(part) { return; }
+        }
+    });
+}
+
+/// Replaces the matches in `s` with the outputs of `replacement`, which runs on the object of named captures of each match.
+///
+/// If `replacement` has multiple outputs, there is an output for each combination, with the last match varying fastest.
+fn replace_matches(replacement: &Filter, context: &Context, s: &str, matches: Vec<RegexMatch>, output: &ValueSender) {
+    let mut results = vec![String::new()];
+    let mut start = 0;
+    for regex_match in matches {
+        let mut replacements = vec![];
+        for replaced in replacement.run_value(context.clone(), regex_match.capture_object(s)) {
+            match replaced {
+                Value::String(replaced) => { replacements.push(String::from(replaced)); }
+                Value::Exception(name, meta) => {
+                    output.send(Value::Exception(name, meta));
+                    return;
+                }
+                replaced => {
+                    output.send(Value::type_error(format!("a replacement must be a string, not {}", replaced.type_name())));
+                    return;
+                }
+            }
+        }
+        let between = &s[start..regex_match.start];
+        results = results.into_iter().flat_map(|result| {
+            let prefix = result + between;
+            replacements.iter().map(move |replaced| prefix.clone() + replaced)
+        }).collect();
+        start = regex_match.end;
+    }
+    for result in results {
+        if !output.send(Value::String(UString::from(result + &s[start..]))) { return; }
+    }
+}
+
+/// `sub($re; str)` and `sub($re; str; $flags)`: the input string with the first match of the regular expression, or all of them with the `g` flag, replaced with the output of `str`.
+///
+/// `str` runs on an object of the named captures of the match, so they can be used like in `sub("(?P<x>a)"; "\(.x)!")`.
+fn sub(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_match(args, 2, "", input, output, |ctxt, s, matches, output| {
+        replace_matches(&args[1], ctxt, s, matches, output);
+    });
+}
+
+/// `gsub($re; str)` and `gsub($re; str; $flags)`: like `sub` with the `g` flag.
+fn gsub(args: &[Filter], input: Receiver, output: Sender) {
+    for_each_match(args, 2, "g", input, output, |ctxt, s, matches, output| {
+        replace_matches(&args[1], ctxt, s, matches, output);
+    });
+}
+
+/// The exception raised when an operator is not defined for the types of its operands.
+fn operand_error(symbol: &str, lhs: Value, rhs: Value) -> Value {
+    Value::exception("type", vec![
+        ("message", Value::String(UString::from(format!("{} and {} cannot be combined using `{}`", lhs.type_name(), rhs.type_name(), symbol)))),
+        ("operator", Value::String(UString::from(symbol))),
+        ("lhs", lhs),
+        ("rhs", rhs)
+    ])
+}
+
+/// The exception raised when dividing by zero.
+fn zero_division(symbol: &str, lhs: BigRational, rhs: BigRational) -> Value {
+    Value::exception("zeroDivision", vec![
+        ("message", Value::String(UString::from("cannot divide by zero"))),
+        ("operator", Value::String(UString::from(symbol))),
+        ("lhs", Value::Number(lhs)),
+        ("rhs", Value::Number(rhs))
+    ])
+}
+
+fn add(lhs: Value, rhs: Value) -> Value {
+    match (lhs, rhs) {
+        (Value::Null, rhs) => rhs,
+        (lhs, Value::Null) => lhs,
+        (Value::Number(l), Value::Number(r)) => Value::Number(l + r),
+        (Value::String(l), Value::String(r)) => Value::String(l.into_iter().chain(r.into_iter()).collect()),
+        (Value::Array(l), Value::Array(r)) => Value::Array(l.into_iter().chain(r.into_iter()).collect()),
+        (Value::Object(mut l), Value::Object(r)) => {
+            for (k, v) in r {
+                l.insert(k, v);
+            }
+            Value::Object(l)
+        }
+        (lhs, rhs) => operand_error("+", lhs, rhs)
+    }
+}
+
+fn subtract(lhs: Value, rhs: Value) -> Value {
+    match (lhs, rhs) {
+        (Value::Number(l), Value::Number(r)) => Value::Number(l - r),
+        (Value::Array(l), Value::Array(r)) => Value::Array(l.into_iter().filter(|item| !r.iter().any(|r_item| r_item == item)).collect()),
+        (Value::Object(mut l), Value::Object(r)) => {
+            for (k, _) in r {
+                l.remove(&k);
+            }
+            Value::Object(l)
+        }
+        (lhs, rhs) => operand_error("-", lhs, rhs)
+    }
+}
+
+/// Merges `rhs` into `lhs`, recursively merging values which are objects on both sides.
+fn deep_merge(mut lhs: Object<HashableValue, Value>, rhs: Object<HashableValue, Value>) -> Object<HashableValue, Value> {
+    for (k, r) in rhs {
+        let merged = match (lhs.remove(&k), r) {
+            (Some(Value::Object(l)), Value::Object(r)) => Value::Object(deep_merge(l, r)),
+            (_, r) => r
+        };
+        lhs.insert(k, merged);
+    }
+    lhs
+}
+
+fn multiply(lhs: Value, rhs: Value) -> Value {
+    match (lhs, rhs) {
+        (Value::Number(l), Value::Number(r)) => Value::Number(l * r),
+        (Value::String(s), Value::Number(n)) | (Value::Number(n), Value::String(s)) => {
+            // repeat the string, or produce null if it would be repeated zero times
+            let count = if n.is_negative() { 0 } else { n.floor().to_integer().to_usize().unwrap_or(0) };
+            if count == 0 {
+                return Value::Null;
+            }
+            Value::String((0..count).flat_map(|_| s.clone().into_iter()).collect())
+        }
+        (Value::Object(l), Value::Object(r)) => Value::Object(deep_merge(l, r)),
+        (lhs, rhs) => operand_error("*", lhs, rhs)
+    }
+}
+
+fn divide(lhs: Value, rhs: Value) -> Value {
+    match (lhs, rhs) {
+        (Value::Number(l), Value::Number(r)) => {
+            if r.is_zero() {
+                zero_division("/", l, r)
+            } else {
+                Value::Number(l / r)
+            }
+        }
+        (Value::String(l), Value::String(r)) => {
+            // split the string at each occurrence of the separator
+            let l = String::from(&l);
+            let r = String::from(&r);
+            Value::Array(if l.is_empty() {
+                Array::new()
+            } else if r.is_empty() {
+                l.chars().map(|c| Value::String(UString::from(c.to_string()))).collect()
+            } else {
+                l.split(&r[..]).map(|part| Value::String(UString::from(part))).collect()
+            })
+        }
+        (lhs, rhs) => operand_error("/", lhs, rhs)
+    }
+}
+
+fn modulo(lhs: Value, rhs: Value) -> Value {
+    match (lhs, rhs) {
+        (Value::Number(l), Value::Number(r)) => {
+            if r.is_zero() {
+                zero_division("%", l, r)
+            } else {
+                Value::Number(l % r)
+            }
+        }
+        (lhs, rhs) => operand_error("%", lhs, rhs)
+    }
+}
+
+/// The value as a string for formats which work on text: strings as they are, and other values as JSON.
+fn text(value: Value) -> String {
+    match value {
+        Value::String(s) => String::from(s),
+        value => value.to_json()
+    }
+}
+
+/// The fields of a `@csv` or `@tsv` row, with strings escaped using `escape`.
+fn row_fields<F: Fn(String) -> String>(format: &str, value: Value, escape: F) -> Result<Vec<String>, Value> {
+    let row = match value {
+        Value::Array(row) => row,
+        value => { return Err(Value::type_error(format!("cannot {}-format {}, only an array", format, value.type_name()))); }
+    };
+    let mut fields = vec![];
+    for field in row {
+        fields.push(match field {
+            Value::Null => String::new(),
+            Value::String(s) => escape(String::from(s)),
+            field @ Value::Boolean(_) | field @ Value::Number(_) => field.to_json(),
+            field => { return Err(Value::type_error(format!("{} is not valid in a {} row", field.type_name(), format))); }
+        });
+    }
+    Ok(fields)
+}
+
+/// `@csv`: an array as a row of comma-separated values, with strings quoted.
+fn csv(value: Value) -> Value {
+    match row_fields("csv", value, |s| format!("\"{}\"", s.replace("\"", "\"\""))) {
+        Ok(fields) => Value::String(UString::from(fields.join(","))),
+        Err(exception) => exception
+    }
+}
+
+/// `@tsv`: an array as a row of tab-separated values, with backslashes, tabs, and line breaks in strings escaped.
+fn tsv(value: Value) -> Value {
+    match row_fields("tsv", value, |s| s.replace("\\", "\\\\").replace("\t", "\\t").replace("\n", "\\n").replace("\r", "\\r")) {
+        Ok(fields) => Value::String(UString::from(fields.join("\t"))),
+        Err(exception) => exception
+    }
+}
+
+/// `@html`: the text of the value, with the characters `<>&'"` escaped as HTML entities.
+fn html(value: Value) -> Value {
+    let mut result = String::new();
+    for c in text(value).chars() {
+        match c {
+            '<' => { result.push_str("&lt;"); }
+            '>' => { result.push_str("&gt;"); }
+            '&' => { result.push_str("&amp;"); }
+            '\'' => { result.push_str("&#39;"); }
+            '"' => { result.push_str("&quot;"); }
+            c => { result.push(c); }
+        }
+    }
+    Value::String(UString::from(result))
+}
+
+/// `@uri`: the text of the value, with all bytes except unreserved URI characters percent-encoded.
+fn uri(value: Value) -> Value {
+    let mut result = String::new();
+    for byte in text(value).bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => { result.push(byte as char); }
+            byte => { result.push_str(&format!("%{:02X}", byte)); }
+        }
+    }
+    Value::String(UString::from(result))
+}
+
+/// `@sh`: the value quoted for use in a POSIX shell command line. An array becomes a space-separated list of quoted words.
+fn sh(value: Value) -> Value {
+    let word = |value: Value| match value {
+        Value::String(s) => Ok(format!("'{}'", String::from(s).replace("'", "'\\''"))),
+        value @ Value::Null | value @ Value::Boolean(_) | value @ Value::Number(_) => Ok(value.to_json()),
+        value => Err(Value::type_error(format!("{} cannot be escaped for shell", value.type_name())))
+    };
+    let words = match value {
+        Value::Array(words) => words.into_iter().map(word).collect::<Result<Vec<_>, _>>(),
+        value => word(value).map(|word| vec![word])
+    };
+    match words {
+        Ok(words) => Value::String(UString::from(words.join(" "))),
+        Err(exception) => exception
+    }
+}
+
+const BASE64_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// `@base64`: the text of the value, encoded as base64 with padding.
+fn base64(value: Value) -> Value {
+    let mut result = String::new();
+    for chunk in text(value).as_bytes().chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    Value::String(UString::from(result))
+}
+
+/// `@base64d`: the inverse of `@base64`. Padding is optional, and decoded bytes which are not valid UTF-8 are replaced with U+FFFD.
+fn base64d(value: Value) -> Value {
+    let encoded = text(value);
+    let mut bytes = vec![];
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    for c in encoded.chars().take_while(|&c| c != '=') {
+        let digit = match BASE64_ALPHABET.iter().position(|&b| b as char == c) {
+            Some(digit) => digit as u32,
+            None => { return Value::exception("base64", vec![("message", Value::String(UString::from(format!("{:?} is not valid base64 data", encoded))))]); }
+        };
+        bits = bits << 6 | digit;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count & 0xff) as u8);
+        }
+    }
+    Value::String(UString::from(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+#[test]
+fn test_arithmetic() {
+    use util::run;
+
+    // numbers are exact rationals
+    assert_eq!(run("1 + 2, 1 / 3, 0.1 + 0.2, 7 % 3, -7 % 3, 5 - 8, 2 * 0.5"), vec!["3", "1/3", "3/10", "1", "-1", "-3", "1"]);
+    assert_eq!(run(r#""ab" + "cd", [1, 2] + [3], {"a": 1} + {"b": 2, "a": 3}, null + 1, [1, 2, 3, 1] - [1], "ab" * 3, "ab" * 0, "a,b" / ",""#), vec![
+        r#""abcd""#, "[1, 2, 3]", r#"{"a": 3, "b": 2}"#, "1", "[2, 3]", r#""ababab""#, "null", r#"["a", "b"]"#
+    ]);
+    assert_eq!(run(r#"{"a": {"b": 1}} * {"a": {"c": 2}}"#), vec![r#"{"a": {"b": 1, "c": 2}}"#]);
+    // exceptions carry the operator and operands
+    assert_eq!(run("1 / 0"), vec![r#"raise "zeroDivision" {"message": "cannot divide by zero", "operator": "/", "lhs": 1, "rhs": 0}"#]);
+    assert_eq!(run("1 % 0"), vec![r#"raise "zeroDivision" {"message": "cannot divide by zero", "operator": "%", "lhs": 1, "rhs": 0}"#]);
+    assert_eq!(run(r#""a" - 1"#), vec![r#"raise "type" {"message": "string and number cannot be combined using `-`", "operator": "-", "lhs": "a", "rhs": 1}"#]);
+}
+
+#[test]
+fn test_recurse() {
+    use util::{run, run_with_input};
+
+    assert_eq!(run(r#"[[1, [2]], {"a": 3}] | [..], [paths], [recurse(.[]?; . != 2)]"#), vec![
+        r#"[[[1, [2]], {"a": 3}], [1, [2]], 1, [2], 2, {"a": 3}, 3]"#,
+        r#"[[0], [0, 0], [0, 1], [0, 1, 0], [1], [1, "a"]]"#,
+        r#"[[[1, [2]], {"a": 3}], [1, [2]], 1, [2], {"a": 3}, 3]"#
+    ]);
+    // each level holds the next one and 100 numbers, so outputting every subtree would copy about 50 million numbers
+    let mut input = Value::Null;
+    for _ in 0..1000 {
+        input = Value::Array(Array::from(Some(input).into_iter().chain((0..100).map(|n| Value::Number(BigRational::from_integer(FromPrimitive::from_usize(n).unwrap())))).collect::<Vec<_>>()));
+    }
+    assert_eq!(run_with_input("first(..) | .[100]", input), vec!["99"]);
+}
+
+#[test]
+fn test_regex() {
+    use util::run;
+
+    // offsets and lengths are measured in code points
+    assert_eq!(run(r#""äö-12" | match("(?P<n>\\d+)") | [.offset, .length, .captures[0].offset, .captures[0].name]"#), vec![r#"[3, 2, 3, "n"]"#]);
+    assert_eq!(run(r#""aBc" | test("b"), test("b"; "i"), [scan("[a-z]"; "gi")], capture("(?P<x>B)")"#), vec!["false", "true", r#"["a", "B", "c"]"#, r#"{"x": "B"}"#]);
+    assert_eq!(run(r#""a, b,c" | split(", *"; null), sub(",(?P<s> *)"; ";\(.s)"), gsub(","; "")"#), vec![r#"["a", "b", "c"]"#, r#""a; b,c""#, r#""a bc""#]);
+}
+
+#[test]
+fn test_builtins() {
+    use util::run;
+
+    assert_eq!(run("[1, null, false, 0] | map(not)"), vec!["[false, true, true, false]"]);
+    // prelude functions keep using the builtins they were defined with when those are redefined
+    assert_eq!(run("def empty: 1; def not: 2; def first(f): 3;; [1, 2] | map(select(. > 1)), any(. > 1), all(. > 1)"), vec!["[2]", "true", "false"]);
+    assert_eq!(run(r#"[3, 1, 2] | sort, map(. * 2), add, any(. > 2), all(. > 2), (map(select(. > 1)) | length)"#), vec!["[1, 2, 3]", "[6, 2, 4]", "6", "true", "false", "2"]);
+    assert_eq!(run(r#"[{"a": 1, "b": 2}, {"a": 0}, {"a": 1, "b": 1}] | group_by(.a), unique_by(.a), min_by(.a), max_by(.a)"#), vec![
+        r#"[[{"a": 0}], [{"a": 1, "b": 2}, {"a": 1, "b": 1}]]"#,
+        r#"[{"a": 0}, {"a": 1, "b": 2}]"#,
+        r#"{"a": 0}"#,
+        r#"{"a": 1, "b": 1}"#
+    ]);
+    assert_eq!(run(r#""a,b, cd, efg" | indices(", "), index(", "), rindex(", "), ("[1, {\"x\": null}]" | fromjson), ("12" | tonumber)"#), vec!["[3, 7]", "3", "7", r#"[1, {"x": null}]"#, "12"]);
+    assert_eq!(run(r#""a, b,,c" | split(","), split(", "), split(""), ("" | split(","))"#), vec![r#"["a", " b", "", "c"]"#, r#"["a", "b,,c"]"#, r#"["a", ",", " ", "b", ",", ",", "c"]"#, "[]"]);
+    // `tojson` writes numbers in decimal notation, so that `fromjson` can read them back
+    assert_eq!(run(r#"[1.5, -0.25, 1e-7, "a\u0001\n\"b", {"x": [null, true]}] | tojson, ((tojson | fromjson) == .), ([1.5, "b"] | join("/"))"#), vec![
+        r#""[1.5,-0.25,1e-7,\"a\\u0001\\n\\\"b\",{\"x\":[null,true]}]""#,
+        "true",
+        r#""1.5/b""#
+    ]);
+    assert_eq!(run(r#"{"a": [1, [2], {"b": 3}]} | contains({"a": [{"b": 3}]}), keys, has("b"), (.a | flatten | length), ([1, null, "c"] | join("-"))"#), vec!["true", r#"["a"]"#, "false", "3", r#""1--c""#]);
 }
@@ -1,4 +1,6 @@
 use std::{mem, thread};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use chan;
 
@@ -8,20 +10,74 @@ use lang::{Context, Filter, Value};
 
 pub struct Sender {
     pub context: eventual::Complete<Context, ()>,
-    pub values: chan::Sender<Value>
-    //TODO namespaces
+    pub values: ValueSender
 }
 
 pub struct Receiver {
     pub context: eventual::Future<Context, ()>,
-    pub values: chan::Receiver<Value>
-    //TODO namespaces
+    pub values: ValueReceiver
+}
+
+/// The sending end of a value channel.
+#[derive(Clone)]
+pub struct ValueSender {
+    values: chan::Sender<Value>,
+    closed: Arc<AtomicBool>
+}
+
+impl ValueSender {
+    /// Sends a value, unless the receiving end has been dropped.
+    ///
+    /// Returns `false` if the value was not sent. Producers should then stop, which is what allows a consumer like `limit` to end an infinite generator.
+    pub fn send(&self, value: Value) -> bool {
+        if self.is_closed() { return false; }
+        self.values.send(value);
+        true
+    }
+
+    /// Returns true if the receiving end has been dropped, so that no more values are needed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+/// The receiving end of a value channel. Dropping it tells the sending end to stop producing values.
+pub struct ValueReceiver {
+    values: chan::Receiver<Value>,
+    closed: Arc<AtomicBool>
+}
+
+impl Drop for ValueReceiver {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl IntoIterator for ValueReceiver {
+    type Item = Value;
+    type IntoIter = Values;
+
+    fn into_iter(self) -> Values {
+        Values::new(self)
+    }
 }
 
 impl Receiver {
     /// A closed receiver with no values.
     pub fn empty(context: Context) -> Receiver {
-        let (_, val_rx) = chan::async();
+        let (_, val_rx) = value_channel();
+        Receiver {
+            context: eventual::Future::of(context),
+            values: val_rx
+        }
+    }
+
+    /// A closed receiver which yields the given values.
+    pub fn from_values<I: IntoIterator<Item = Value>>(context: Context, values: I) -> Receiver {
+        let (val_tx, val_rx) = value_channel();
+        for value in values {
+            val_tx.send(value);
+        }
         Receiver {
             context: eventual::Future::of(context),
             values: val_rx
@@ -47,12 +103,12 @@ impl Receiver {
     ///
     /// Returns `dst`'s context future sender.
     pub fn forward_values(&mut self, dst: Sender) -> eventual::Complete<Context, ()> {
-        let (_, val_rx) = chan::async();
+        let (_, val_rx) = value_channel();
         let vals_to_forward = mem::replace(&mut self.values, val_rx);
         let Sender { context, values } = dst;
         thread::spawn(move || {
             for val in vals_to_forward {
-                values.send(val);
+                if !values.send(val) { break; }
             }
         });
         context
@@ -70,8 +126,9 @@ impl Receiver {
         });
         thread::spawn(move || {
             for val in values {
-                val_tx1.send(val.clone());
-                val_tx2.send(val);
+                let sent1 = val_tx1.send(val.clone());
+                let sent2 = val_tx2.send(val);
+                if !sent1 && !sent2 { break; }
             }
         });
         (rx1, rx2)
@@ -80,16 +137,49 @@ impl Receiver {
 
 impl IntoIterator for Receiver {
     type Item = Value;
-    type IntoIter = chan::Iter<Value>;
+    type IntoIter = Values;
 
-    fn into_iter(self) -> chan::Iter<Value> {
+    fn into_iter(self) -> Values {
         self.values.into_iter()
     }
 }
 
+/// An iterator over the values sent on a channel.
+///
+/// An uncaught exception terminates the stream: it is yielded as the last value, and anything sent after it is discarded.
+///
+/// Dropping the iterator drops the receiving end, so a producer which is not done yet will stop.
+pub struct Values {
+    values: ValueReceiver,
+    done: bool
+}
+
+impl Values {
+    /// Iterates over the values received on `values`, up to and including the first exception.
+    pub fn new(values: ValueReceiver) -> Values {
+        Values {
+            values: values,
+            done: false
+        }
+    }
+}
+
+impl Iterator for Values {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        if self.done { return None; }
+        let value = self.values.values.recv();
+        if let Some(Value::Exception(_, _)) = value {
+            self.done = true;
+        }
+        value
+    }
+}
+
 pub fn channel() -> (Sender, Receiver) {
     let (ctxt_tx, ctxt_fut) = eventual::Future::pair();
-    let (val_tx, val_rx) = chan::async();
+    let (val_tx, val_rx) = value_channel();
     let tx = Sender {
         context: ctxt_tx,
         values: val_tx
@@ -100,3 +190,18 @@ pub fn channel() -> (Sender, Receiver) {
     };
     (tx, rx)
 }
+
+/// Creates a value channel without a context.
+pub fn value_channel() -> (ValueSender, ValueReceiver) {
+    let (val_tx, val_rx) = chan::async();
+    let closed = Arc::new(AtomicBool::new(false));
+    let tx = ValueSender {
+        values: val_tx,
+        closed: closed.clone()
+    };
+    let rx = ValueReceiver {
+        values: val_rx,
+        closed: closed
+    };
+    (tx, rx)
+}
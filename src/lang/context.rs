@@ -1,15 +1,30 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::sync::Arc;
 
 use num::BigRational;
 
-use lang::Filter;
+use unicode::UString;
+
+use lang::{Filter, Value};
+use lang::value::Function;
+use util::{FormatFn, OperatorFn};
 
 #[derive(Clone, Debug)]
 pub enum PrecedenceGroup {
+    /// The right-associative alternative operator `//`
+    Alternative,
+    And,
     AndThen,
-    Circumfix
+    /// Right-associative assignment operators: `=`, `|=`, and an update-assignment like `+=` for each of the given operators
+    Assign(Vec<OperatorFn>),
+    Circumfix,
+    Comma,
+    /// Left-associative binary operators, each labeled with its symbol and applied to every combination of the operands' outputs
+    Infix(Vec<OperatorFn>),
+    Or,
+    Pipe,
+    Postfix
 }
 
 #[derive(Clone)]
@@ -17,11 +32,21 @@ pub struct Context {
     /// A function called each time the parser constructs a new filter anywhere in the syntax tree. If it returns false, the filter is replaced with one that generates an exception.
     pub filter_allowed: Arc<Box<Fn(&Filter) -> bool + Send + Sync>>,
     /// The context's operators, in decreasing precedence.
-    pub operators: BTreeMap<BigRational, PrecedenceGroup>
+    pub operators: BTreeMap<BigRational, PrecedenceGroup>,
+    /// Functions which are not defined in an enclosing filter, by name and arity. A top-level `def` adds its function here for the rest of the code, including after `;;`.
+    pub functions: HashMap<(UString, usize), Function>,
+    /// While parsing, the functions defined in an enclosing filter, including the parameters of enclosing functions, by name and arity. Calls to these are resolved when the enclosing filter runs, calls to `functions` when they are parsed.
+    pub local_functions: HashSet<(UString, usize)>,
+    /// Formats like `@base64`, by name without the `@`, for use as filters or to format the values interpolated into a string.
+    pub formats: HashMap<UString, FormatFn>,
+    /// Global variables, which are visible everywhere unless shadowed by an `as` binding. A binding without a body, like `f as $x`, adds its variables here for the code after `;;`.
+    pub variables: HashMap<UString, Value>,
+    /// Settings exported to the environment running the code, like the REPL prompt.
+    pub settings: HashMap<UString, Value>
 }
 
 impl fmt::Debug for Context {
     fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
-        write!(w, "Context {{ filter_allowed: [Fn(&Filter) -> bool], operators: {:?} }}", self.operators)
+        write!(w, "Context {{ filter_allowed: [Fn(&Filter) -> bool], operators: {:?}, functions: {:?}, local_functions: {:?}, formats: {:?}, variables: {:?}, settings: {:?} }}", self.operators, self.functions, self.local_functions, self.formats, self.variables, self.settings)
     }
 }
@@ -1,42 +1,760 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use num::{BigInt, BigRational, FromPrimitive};
+
 use unicode::UString;
 
-use eventual::Async;
+use eventual::{self, Async};
 
+use lang::Context;
 use lang::parser::{self, Code};
-use lang::value::{Value, Object};
-use lang::channel::{Sender, Receiver, channel};
-use util::FilterFn;
+use lang::value::{self, ExceptionName, HashableValue, Value};
+use lang::value::function::Param;
+use lang::channel::{Sender, Receiver, ValueSender, Values, channel};
+use lang::scope::Scope;
+use util::{FilterFn, FormatFn, OperatorFn, PathsFn};
 
 #[derive(Clone, Debug)]
 pub enum Filter {
+    /// `lhs // rhs`
+    Alternative {
+        lhs: Box<Filter>,
+        rhs: Box<Filter>
+    },
+    And {
+        lhs: Box<Filter>,
+        rhs: Box<Filter>
+    },
     AndThen {
         lhs: Box<Filter>,
         remaining_code: Code
     },
+    Apply {
+        function: value::Function,
+        args: Vec<Filter>
+    },
+    Array(Box<Filter>),
+    /// `lhs = rhs`, or an arithmetic update-assignment like `lhs += rhs` if `op` is given
+    Assign {
+        lhs: Box<Filter>,
+        op: Option<OperatorFn>,
+        rhs: Box<Filter>
+    },
+    Bind {
+        source: Box<Filter>,
+        patterns: Vec<Pattern>,
+        body: Box<Filter>
+    },
+    /// `source as $name` without a body, which binds the variables in the context, so they are visible after `;;` and on later lines in the REPL
+    BindGlobal {
+        source: Box<Filter>,
+        patterns: Vec<Pattern>
+    },
+    Break(UString),
+    Call {
+        name: UString,
+        args: Vec<Filter>
+    },
+    Comma {
+        lhs: Box<Filter>,
+        rhs: Box<Filter>
+    },
+    Conditional {
+        condition: Box<Filter>,
+        then_branch: Box<Filter>,
+        else_branch: Box<Filter>
+    },
+    Constant(Value),
     Custom {
         attributes: Vec<Filter>,
-        run: Box<FilterFn>
+        run: Box<FilterFn>,
+        /// How to run the filter in path mode. If this is `None`, its outputs are not valid paths.
+        paths: Option<Box<PathsFn>>
+    },
+    Def {
+        function: value::Function,
+        rest: Box<Filter>,
+        /// Whether the function is added to the output context, so it remains available after `;;` and on later lines in the REPL. Only definitions at the top level of the code are exported.
+        exported: bool
+    },
+    Empty,
+    Foreach {
+        source: Box<Filter>,
+        pattern: Pattern,
+        init: Box<Filter>,
+        update: Box<Filter>,
+        extract: Option<Box<Filter>>
+    },
+    /// A format like `@base64` used as a filter, which formats its input
+    Format(FormatFn),
+    Identity,
+    Index {
+        lhs: Box<Filter>,
+        index: Box<Filter>
+    },
+    Infix {
+        lhs: Box<Filter>,
+        op: OperatorFn,
+        rhs: Box<Filter>
+    },
+    /// A string literal with interpolated filters like `"foo \\(.bar)"`, optionally prefixed with a format like `@sh "foo \\(.bar)"`.
+    ///
+    /// `parts` are the literal parts around the interpolations, so there is one more part than interpolations. Without a format, interpolated strings are inserted as they are and other values as JSON.
+    Interpolation {
+        parts: Vec<UString>,
+        interpolations: Vec<Filter>,
+        format: Option<FormatFn>
+    },
+    Iterate(Box<Filter>),
+    Label {
+        name: UString,
+        body: Box<Filter>
+    },
+    Negate(Box<Filter>),
+    Object(Vec<(Filter, Filter)>),
+    Or {
+        lhs: Box<Filter>,
+        rhs: Box<Filter>
+    },
+    Pipe {
+        lhs: Box<Filter>,
+        rhs: Box<Filter>
+    },
+    Raise {
+        name: Box<Filter>,
+        meta: Box<Filter>
+    },
+    Reduce {
+        source: Box<Filter>,
+        pattern: Pattern,
+        init: Box<Filter>,
+        update: Box<Filter>
+    },
+    Slice {
+        lhs: Box<Filter>,
+        from: Option<Box<Filter>>,
+        to: Option<Box<Filter>>
     },
-    Empty
+    Try {
+        body: Box<Filter>,
+        handler: Option<Box<Filter>>
+    },
+    /// `lhs |= rhs`
+    Update {
+        lhs: Box<Filter>,
+        rhs: Box<Filter>
+    },
+    Variable(UString)
+}
+
+/// The left-hand side of a variable binding, like the `[$a, {b: $c}]` in `. as [$a, {b: $c}] | ...`.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Array(Vec<Pattern>),
+    Object(Vec<(Filter, Pattern)>),
+    Variable(UString)
+}
+
+impl Pattern {
+    /// The names of all variables bound by this pattern.
+    pub fn variables(&self) -> Vec<UString> {
+        match *self {
+            Pattern::Array(ref items) => items.iter().flat_map(|item| item.variables()).collect(),
+            Pattern::Object(ref entries) => entries.iter().flat_map(|&(_, ref value)| value.variables()).collect(),
+            Pattern::Variable(ref name) => vec![name.clone()]
+        }
+    }
+
+    /// Replaces references to the variables in `scope` within the key filters of this pattern.
+    fn bind(&self, scope: &Scope) -> Pattern {
+        match *self {
+            Pattern::Array(ref items) => Pattern::Array(items.iter().map(|item| item.bind(scope)).collect()),
+            Pattern::Object(ref entries) => Pattern::Object(entries.iter().map(|&(ref key, ref value)| (key.bind(scope), value.bind(scope))).collect()),
+            Pattern::Variable(ref name) => Pattern::Variable(name.clone())
+        }
+    }
+
+    /// Binds the variables in this pattern to the matching parts of `value`, adding them to `scope`.
+    ///
+    /// Since key filters may produce multiple outputs, this returns one scope for each combination of their outputs. Key filters run on `input`, the input of the binding. If `value` does not match the pattern, the exception is returned instead.
+    fn destructure(&self, context: &Context, input: &Value, value: Value, mut scope: Scope) -> Result<Vec<Scope>, Value> {
+        match *self {
+            Pattern::Array(ref items) => {
+                let mut scopes = vec![scope];
+                for (idx, item) in items.iter().enumerate() {
+                    let item_value = value.index(&Value::Number(BigRational::from_integer(BigInt::from_usize(idx).unwrap())));
+                    if let Value::Exception(_, _) = item_value {
+                        return Err(item_value);
+                    }
+                    let mut new_scopes = vec![];
+                    for scope in scopes {
+                        new_scopes.extend(try!(item.destructure(context, input, item_value.clone(), scope)));
+                    }
+                    scopes = new_scopes;
+                }
+                Ok(scopes)
+            }
+            Pattern::Object(ref entries) => {
+                let mut scopes = vec![scope];
+                for &(ref key, ref entry) in entries {
+                    let mut new_scopes = vec![];
+                    for key_value in key.run_value(context.clone(), input.clone()) {
+                        let entry_value = value.index(&key_value);
+                        if let Value::Exception(_, _) = entry_value {
+                            return Err(entry_value);
+                        }
+                        for scope in &scopes {
+                            new_scopes.extend(try!(entry.destructure(context, input, entry_value.clone(), scope.clone())));
+                        }
+                    }
+                    scopes = new_scopes;
+                }
+                Ok(scopes)
+            }
+            Pattern::Variable(ref name) => {
+                scope.insert(name.clone(), value);
+                Ok(vec![scope])
+            }
+        }
+    }
+}
+
+/// The id for the next label, used to tell apart the labels of different runs of the same `label` filter, such as in recursive functions.
+static NEXT_LABEL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Completes the output context with the input context, then calls `f` for each input value.
+///
+/// Exceptions are not passed to `f` but forwarded to the output unchanged.
+pub fn for_each_value<F: FnMut(&Context, Value, &ValueSender)>(input: Receiver, output: Sender, mut f: F) {
+    let Receiver { context: in_ctxt, values } = input;
+    let Sender { context, values: out_values } = output;
+    let ctxt = in_ctxt.await().expect("failed to get input context");
+    context.complete(ctxt.clone());
+    for value in Values::new(values) {
+        if let Value::Exception(_, _) = value {
+            out_values.send(value);
+        } else {
+            f(&ctxt, value, &out_values);
+        }
+        if out_values.is_closed() {
+            // the rest of the input is not needed once the output's receiver is gone
+            return;
+        }
+    }
+}
+
+/// Runs the source of a binding without a body on each input value, and binds the variables of the matching pattern in the context.
+///
+/// The variables are bound again for each output of the source, so the last output is the one that remains. Returns the first exception raised by the source or while destructuring.
+fn bind_globals(ctxt: &mut Context, source: &Filter, patterns: &[Pattern], values: Values) -> Result<(), Value> {
+    let names = patterns.iter().flat_map(|pattern| pattern.variables()).collect::<Vec<_>>();
+    let mut unmatched = Scope::default();
+    for name in &names {
+        unmatched.insert(name.clone(), Value::Null);
+    }
+    for value in values {
+        if let Value::Exception(_, _) = value { return Err(value); }
+        for source_value in source.run_value(ctxt.clone(), value.clone()) {
+            if let Value::Exception(_, _) = source_value { return Err(source_value); }
+            // like in `?//`, the first pattern which matches is used, and the variables of the others are bound to `null`
+            let mut scopes = vec![];
+            for (idx, pattern) in patterns.iter().enumerate() {
+                match pattern.destructure(ctxt, &value, source_value.clone(), unmatched.clone()) {
+                    Ok(pattern_scopes) => {
+                        scopes = pattern_scopes;
+                        break;
+                    }
+                    Err(exception) => if idx + 1 == patterns.len() { return Err(exception); }
+                }
+            }
+            for scope in scopes {
+                for name in &names {
+                    if let Some(bound_value) = scope.get(name) {
+                        ctxt.variables.insert(name.clone(), bound_value.clone());
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the scopes in which the body of a function runs when it is called with the given arguments on a single input value.
+///
+/// The function can call itself, and its filter parameters are defined as functions without arguments. The body runs once for each combination of the outputs of the arguments for value parameters, which run on the input of the call. An exception from one of those arguments is returned instead.
+fn call_scopes(function: &value::Function, args: &[Filter], ctxt: &Context, value: &Value) -> Result<Vec<Scope>, Value> {
+    let mut scope = Scope::default();
+    scope.insert_function(function.clone());
+    let mut scopes = vec![scope];
+    for (param, arg) in function.params.iter().zip(args) {
+        match *param {
+            Param::Filter(ref name) => {
+                for scope in &mut scopes {
+                    scope.insert_function(value::Function {
+                        name: name.clone(),
+                        params: vec![],
+                        body: Arc::new(arg.clone())
+                    });
+                }
+            }
+            Param::Value(ref name) => {
+                let mut new_scopes = vec![];
+                for arg_value in arg.run_value(ctxt.clone(), value.clone()) {
+                    if let Value::Exception(_, _) = arg_value {
+                        return Err(arg_value);
+                    }
+                    for scope in &scopes {
+                        let mut scope = scope.clone();
+                        scope.insert(name.clone(), arg_value.clone());
+                        scope.insert_function(value::Function {
+                            name: name.clone(),
+                            params: vec![],
+                            body: Arc::new(Filter::Constant(arg_value.clone()))
+                        });
+                        new_scopes.push(scope);
+                    }
+                }
+                scopes = new_scopes;
+            }
+        }
+    }
+    Ok(scopes)
+}
+
+/// Calls the function with the given arguments on a single input value.
+fn apply(function: &value::Function, args: &[Filter], ctxt: &Context, value: Value, output: &ValueSender) {
+    let scopes = match call_scopes(function, args, ctxt, &value) {
+        Ok(scopes) => scopes,
+        Err(exception) => {
+            output.send(exception);
+            return;
+        }
+    };
+    for scope in scopes {
+        for body_value in function.body.bind(&scope).run_value(ctxt.clone(), value.clone()) {
+            if !output.send(body_value) { return; }
+        }
+    }
+}
+
+/// Calls the function with the given arguments on a single input value in path mode, like `Filter::run_paths`.
+fn apply_paths(function: &value::Function, args: &[Filter], ctxt: &Context, path: Vec<Value>, value: Value, output: &mut FnMut(Vec<Value>, Value) -> bool) -> bool {
+    let scopes = match call_scopes(function, args, ctxt, &value) {
+        Ok(scopes) => scopes,
+        Err(exception) => { return output(vec![], exception); }
+    };
+    for scope in scopes {
+        if !function.body.bind(&scope).run_paths(ctxt, path.clone(), value.clone(), output) { return false; }
+    }
+    true
+}
+
+/// The exception raised when a filter run in path mode produces a value which is not part of its input.
+fn invalid_path(value: Value) -> Value {
+    Value::exception("invalidPath", vec![
+        ("message", Value::String(UString::from(format!("invalid path expression with result {}", value)))),
+        ("value", value)
+    ])
 }
 
 impl Filter {
+    /// Returns a copy of this filter where references to the variables bound in `scope` are replaced with their values.
+    pub fn bind(&self, scope: &Scope) -> Filter {
+        use self::Filter::*;
+
+        let bind_box = |f: &Box<Filter>| Box::new(f.bind(scope));
+        match *self {
+            Alternative { ref lhs, ref rhs } => Alternative { lhs: bind_box(lhs), rhs: bind_box(rhs) },
+            And { ref lhs, ref rhs } => And { lhs: bind_box(lhs), rhs: bind_box(rhs) },
+            AndThen { ref lhs, ref remaining_code } => AndThen { lhs: bind_box(lhs), remaining_code: remaining_code.clone() }, // variables are not visible after `;;`
+            Apply { ref function, ref args } => Apply { function: function.clone(), args: args.iter().map(|arg| arg.bind(scope)).collect() }, // the function body has already been bound where it was defined
+            Array(ref inner) => Array(bind_box(inner)),
+            Assign { ref lhs, ref op, ref rhs } => Assign { lhs: bind_box(lhs), op: op.clone(), rhs: bind_box(rhs) },
+            Bind { ref source, ref patterns, ref body } => {
+                let shadowed = patterns.iter().flat_map(|pattern| pattern.variables()).collect::<Vec<_>>();
+                Bind {
+                    source: bind_box(source),
+                    patterns: patterns.iter().map(|pattern| pattern.bind(scope)).collect(),
+                    body: Box::new(body.bind(&scope.without(&shadowed, &[])))
+                }
+            }
+            BindGlobal { ref source, ref patterns } => BindGlobal { source: bind_box(source), patterns: patterns.iter().map(|pattern| pattern.bind(scope)).collect() },
+            Break(ref name) => if let Some(id) = scope.get_label(name) { Constant(Value::Exception(ExceptionName::Break(id), value::Object::default())) } else { Break(name.clone()) },
+            Call { ref name, ref args } => {
+                let args = args.iter().map(|arg| arg.bind(scope)).collect::<Vec<_>>();
+                if let Some(function) = scope.get_function(name, args.len()) {
+                    Apply { function: function.clone(), args: args }
+                } else {
+                    Call { name: name.clone(), args: args }
+                }
+            }
+            Comma { ref lhs, ref rhs } => Comma { lhs: bind_box(lhs), rhs: bind_box(rhs) },
+            Conditional { ref condition, ref then_branch, ref else_branch } => Conditional { condition: bind_box(condition), then_branch: bind_box(then_branch), else_branch: bind_box(else_branch) },
+            Custom { ref attributes, ref run, ref paths } => Custom { attributes: attributes.iter().map(|attr| attr.bind(scope)).collect(), run: run.clone(), paths: paths.clone() },
+            Def { ref function, ref rest, exported } => {
+                // the function itself and its parameters shadow the scope within the body
+                let key = (function.name.clone(), function.arity());
+                let value_params = function.params.iter().filter_map(|param| if let Param::Value(ref name) = *param { Some(name.clone()) } else { None }).collect::<Vec<_>>();
+                let mut shadowed = function.params.iter().map(|param| (param.name().clone(), 0)).collect::<Vec<_>>();
+                shadowed.push(key.clone());
+                Def {
+                    function: value::Function {
+                        name: function.name.clone(),
+                        params: function.params.clone(),
+                        body: Arc::new(function.body.bind(&scope.without(&value_params, &shadowed)))
+                    },
+                    rest: Box::new(rest.bind(&scope.without(&[], &[key]))),
+                    exported: exported
+                }
+            }
+            Foreach { ref source, ref pattern, ref init, ref update, ref extract } => {
+                let inner_scope = scope.without(&pattern.variables(), &[]);
+                Foreach {
+                    source: bind_box(source),
+                    pattern: pattern.bind(scope),
+                    init: bind_box(init),
+                    update: Box::new(update.bind(&inner_scope)),
+                    extract: extract.as_ref().map(|extract| Box::new(extract.bind(&inner_scope)))
+                }
+            }
+            Format(ref format) => Format(format.clone()),
+            Index { ref lhs, ref index } => Index { lhs: bind_box(lhs), index: bind_box(index) },
+            Infix { ref lhs, ref op, ref rhs } => Infix { lhs: bind_box(lhs), op: op.clone(), rhs: bind_box(rhs) },
+            Interpolation { ref parts, ref interpolations, ref format } => Interpolation { parts: parts.clone(), interpolations: interpolations.iter().map(|f| f.bind(scope)).collect(), format: format.clone() },
+            Iterate(ref lhs) => Iterate(bind_box(lhs)),
+            Label { ref name, ref body } => Label { name: name.clone(), body: Box::new(body.bind(&scope.without_label(name))) },
+            Negate(ref operand) => Negate(bind_box(operand)),
+            Object(ref entries) => Object(entries.iter().map(|&(ref key, ref value)| (key.bind(scope), value.bind(scope))).collect()),
+            Or { ref lhs, ref rhs } => Or { lhs: bind_box(lhs), rhs: bind_box(rhs) },
+            Pipe { ref lhs, ref rhs } => Pipe { lhs: bind_box(lhs), rhs: bind_box(rhs) },
+            Raise { ref name, ref meta } => Raise { name: bind_box(name), meta: bind_box(meta) },
+            Reduce { ref source, ref pattern, ref init, ref update } => Reduce {
+                source: bind_box(source),
+                pattern: pattern.bind(scope),
+                init: bind_box(init),
+                update: Box::new(update.bind(&scope.without(&pattern.variables(), &[])))
+            },
+            Slice { ref lhs, ref from, ref to } => Slice { lhs: bind_box(lhs), from: from.as_ref().map(&bind_box), to: to.as_ref().map(&bind_box) },
+            Try { ref body, ref handler } => Try { body: bind_box(body), handler: handler.as_ref().map(&bind_box) },
+            Update { ref lhs, ref rhs } => Update { lhs: bind_box(lhs), rhs: bind_box(rhs) },
+            Variable(ref name) => if let Some(value) = scope.get(name) { Constant(value.clone()) } else { Variable(name.clone()) },
+            Constant(_) | Empty | Identity => self.clone()
+        }
+    }
+
+    /// Runs the filter with a single input value, and returns the receiving end of its output.
+    pub fn run_value(&self, context: Context, value: Value) -> Receiver {
+        match *self {
+            Filter::Constant(ref v) => Receiver::from_values(context, vec![v.clone()]),
+            Filter::Identity => Receiver::from_values(context, vec![value]),
+            _ => Receiver::from_values(context, vec![value]).filter(self)
+        }
+    }
+
+    /// Runs the filter in path mode on a single input `value`, which is found at `path` in the value the path expression started from.
+    ///
+    /// Instead of just its outputs, `output` receives the path of each output along with the output itself. Exceptions are passed with an empty path, and `output` should return false after an exception, since it ends the output. Filters which don't select parts of their input, like literals, raise an `invalidPath` exception.
+    ///
+    /// Returns false if `output` did, meaning no more outputs are needed.
+    pub fn run_paths(&self, context: &Context, path: Vec<Value>, value: Value, output: &mut FnMut(Vec<Value>, Value) -> bool) -> bool {
+        use self::Filter::*;
+
+        let with_component = |path: &[Value], component: Value| path.iter().cloned().chain(Some(component)).collect::<Vec<_>>();
+        match *self {
+            Alternative { ref lhs, ref rhs } => {
+                let mut found = false;
+                let mut stopped = false;
+                lhs.run_paths(context, path.clone(), value.clone(), &mut |lhs_path, lhs_value| {
+                    match lhs_value {
+                        Value::Exception(ExceptionName::Raised(_), _) => false,
+                        lhs_value => {
+                            if !lhs_value.is_truthy() { return true; }
+                            found = true;
+                            if output(lhs_path, lhs_value) { true } else { stopped = true; false }
+                        }
+                    }
+                });
+                if stopped { false } else if found { true } else { rhs.run_paths(context, path, value, output) }
+            }
+            Apply { ref function, ref args } => apply_paths(function, args, context, path, value, output),
+            Bind { ref source, ref patterns, ref body } => {
+                let mut unmatched = Scope::default();
+                for name in patterns.iter().flat_map(|pattern| pattern.variables()) {
+                    unmatched.insert(name, Value::Null);
+                }
+                for source_value in source.run_value(context.clone(), value.clone()) {
+                    if let Value::Exception(_, _) = source_value {
+                        return output(vec![], source_value);
+                    }
+                    'patterns: for (idx, pattern) in patterns.iter().enumerate() {
+                        let is_last = idx + 1 == patterns.len();
+                        let scopes = match pattern.destructure(context, &value, source_value.clone(), unmatched.clone()) {
+                            Ok(scopes) => scopes,
+                            Err(exception) => {
+                                if is_last { return output(vec![], exception); }
+                                continue;
+                            }
+                        };
+                        for scope in scopes {
+                            let mut failed = false;
+                            let cont = body.bind(&scope).run_paths(context, path.clone(), value.clone(), &mut |body_path, body_value| {
+                                if let Value::Exception(ExceptionName::Raised(_), _) = body_value {
+                                    if !is_last {
+                                        failed = true;
+                                        return false;
+                                    }
+                                }
+                                output(body_path, body_value)
+                            });
+                            if failed { continue 'patterns; }
+                            if !cont { return false; }
+                        }
+                        break;
+                    }
+                }
+                true
+            }
+            Call { .. } => {
+                // functions are bound before the filter runs, so the exception for an undefined function is raised by running the call normally
+                for call_value in self.run_value(context.clone(), value) {
+                    return output(vec![], call_value);
+                }
+                true
+            }
+            Comma { ref lhs, ref rhs } => lhs.run_paths(context, path.clone(), value.clone(), output) && rhs.run_paths(context, path, value, output),
+            Conditional { ref condition, ref then_branch, ref else_branch } => {
+                for condition_value in condition.run_value(context.clone(), value.clone()) {
+                    let branch = match condition_value {
+                        Value::Exception(_, _) => { return output(vec![], condition_value); }
+                        ref condition_value if condition_value.is_truthy() => then_branch,
+                        _ => else_branch
+                    };
+                    if !branch.run_paths(context, path.clone(), value.clone(), output) { return false; }
+                }
+                true
+            }
+            Custom { ref attributes, paths: Some(ref paths), .. } => paths(attributes, context, path, value, output),
+            Def { ref function, ref rest, .. } => {
+                let mut scope = Scope::default();
+                scope.insert_function(function.clone());
+                rest.bind(&scope).run_paths(context, path, value, output)
+            }
+            Empty => true,
+            Identity => output(path, value),
+            Index { ref lhs, ref index } => {
+                let mut lhs_paths = vec![];
+                lhs.run_paths(context, path, value.clone(), &mut |lhs_path, lhs_value| {
+                    let is_exception = if let Value::Exception(_, _) = lhs_value { true } else { false };
+                    lhs_paths.push((lhs_path, lhs_value));
+                    !is_exception
+                });
+                for idx in index.run_value(context.clone(), value) {
+                    if let Value::Exception(_, _) = idx {
+                        return output(vec![], idx);
+                    }
+                    for &(ref lhs_path, ref lhs_value) in &lhs_paths {
+                        let item = lhs_value.index(&idx);
+                        let cont = if let Value::Exception(_, _) = item { output(vec![], item) } else { output(with_component(lhs_path, idx.clone()), item) };
+                        if !cont { return false; }
+                    }
+                }
+                true
+            }
+            Iterate(ref lhs) => {
+                lhs.run_paths(context, path, value, &mut |lhs_path, lhs_value| {
+                    match lhs_value {
+                        Value::Array(array) => {
+                            for (idx, item) in array.into_iter().enumerate() {
+                                let idx = Value::Number(BigRational::from_integer(BigInt::from_usize(idx).unwrap()));
+                                if !output(with_component(&lhs_path, idx), item) { return false; }
+                            }
+                            true
+                        }
+                        Value::Object(object) => {
+                            for (key, item) in object {
+                                if !output(with_component(&lhs_path, Value::from(key)), item) { return false; }
+                            }
+                            true
+                        }
+                        Value::Exception(_, _) => output(vec![], lhs_value),
+                        _ => output(vec![], Value::type_error(format!("cannot iterate over {}", lhs_value.type_name())))
+                    }
+                })
+            }
+            Label { ref name, ref body } => {
+                let id = NEXT_LABEL_ID.fetch_add(1, Ordering::SeqCst);
+                let mut scope = Scope::default();
+                scope.insert_label(name.clone(), id);
+                let mut broken = false;
+                let cont = body.bind(&scope).run_paths(context, path, value, &mut |body_path, body_value| {
+                    if let Value::Exception(ExceptionName::Break(break_id), _) = body_value {
+                        if break_id == id {
+                            broken = true;
+                            return false;
+                        }
+                    }
+                    output(body_path, body_value)
+                });
+                cont || broken
+            }
+            Pipe { ref lhs, ref rhs } => {
+                lhs.run_paths(context, path, value, &mut |lhs_path, lhs_value| {
+                    if let Value::Exception(_, _) = lhs_value {
+                        output(lhs_path, lhs_value)
+                    } else {
+                        rhs.run_paths(context, lhs_path, lhs_value, output)
+                    }
+                })
+            }
+            Slice { ref lhs, ref from, ref to } => {
+                let bound_values = |bound: &Option<Box<Filter>>| if let Some(ref f) = *bound {
+                    f.run_value(context.clone(), value.clone()).into_iter().collect::<Vec<_>>()
+                } else {
+                    vec![Value::Null]
+                };
+                let from_values = bound_values(from);
+                let to_values = bound_values(to);
+                let mut lhs_paths = vec![];
+                lhs.run_paths(context, path, value.clone(), &mut |lhs_path, lhs_value| {
+                    let is_exception = if let Value::Exception(_, _) = lhs_value { true } else { false };
+                    lhs_paths.push((lhs_path, lhs_value));
+                    !is_exception
+                });
+                for to_value in &to_values {
+                    for from_value in &from_values {
+                        for &(ref lhs_path, ref lhs_value) in &lhs_paths {
+                            let slice = lhs_value.slice(from_value, to_value);
+                            let cont = if let Value::Exception(_, _) = slice {
+                                output(vec![], slice)
+                            } else {
+                                let component = Value::Object(vec![
+                                    (HashableValue::String(UString::from("start")), from_value.clone()),
+                                    (HashableValue::String(UString::from("end")), to_value.clone())
+                                ].into_iter().collect());
+                                output(with_component(lhs_path, component), slice)
+                            };
+                            if !cont { return false; }
+                        }
+                    }
+                }
+                true
+            }
+            Try { ref body, ref handler } => {
+                let mut caught = None;
+                let cont = body.run_paths(context, path, value, &mut |body_path, body_value| {
+                    match body_value {
+                        Value::Exception(ExceptionName::Raised(_), _) => {
+                            caught = Some(body_value);
+                            false
+                        }
+                        body_value => output(body_path, body_value)
+                    }
+                });
+                match (caught, handler.as_ref()) {
+                    (Some(Value::Exception(ExceptionName::Raised(name), meta)), Some(handler)) => {
+                        // the handler's input is not part of the input of `try`, so its outputs are not valid paths
+                        let caught = Value::Object(vec![
+                            (HashableValue::String(UString::from("name")), Value::String(name)),
+                            (HashableValue::String(UString::from("metadata")), Value::Object(meta))
+                        ].into_iter().collect());
+                        for handler_value in handler.run_value(context.clone(), caught) {
+                            if let Value::Exception(_, _) = handler_value {
+                                return output(vec![], handler_value);
+                            }
+                            return output(vec![], invalid_path(handler_value));
+                        }
+                        true
+                    }
+                    (Some(_), _) => true,
+                    (None, _) => cont
+                }
+            }
+            _ => {
+                for result in self.run_value(context.clone(), value) {
+                    if let Value::Exception(_, _) = result {
+                        return output(vec![], result);
+                    }
+                    return output(vec![], invalid_path(result));
+                }
+                true
+            }
+        }
+    }
+
+    /// Runs the filter in path mode on `value`, and collects the paths of its outputs.
+    pub fn paths(&self, context: &Context, value: &Value) -> Result<Vec<Vec<Value>>, Value> {
+        let mut paths = vec![];
+        let mut exception = None;
+        self.run_paths(context, vec![], value.clone(), &mut |path, path_value| {
+            if let Value::Exception(_, _) = path_value {
+                exception = Some(path_value);
+                return false;
+            }
+            paths.push(path);
+            true
+        });
+        if let Some(exception) = exception { Err(exception) } else { Ok(paths) }
+    }
+
     pub fn run(&self, input: Receiver, output: Sender) {
         use self::Filter::*;
 
         match *self {
+            Alternative { ref lhs, ref rhs } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let mut found = false;
+                    for lhs_value in lhs.run_value(ctxt.clone(), value.clone()) {
+                        match lhs_value {
+                            Value::Exception(ExceptionName::Raised(_), _) => {} // exceptions from lhs are suppressed and end its output
+                            lhs_value => if lhs_value.is_truthy() {
+                                found = true;
+                                if !output.send(lhs_value) { return; }
+                            }
+                        }
+                    }
+                    if !found {
+                        for rhs_value in rhs.run_value(ctxt.clone(), value) {
+                            if !output.send(rhs_value) { return; }
+                        }
+                    }
+                });
+            }
+            And { ref lhs, ref rhs } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    for lhs_value in lhs.run_value(ctxt.clone(), value.clone()) {
+                        if let Value::Exception(_, _) = lhs_value {
+                            if !output.send(lhs_value) { return; }
+                        } else if lhs_value.is_truthy() {
+                            for rhs_value in rhs.run_value(ctxt.clone(), value.clone()) {
+                                if !output.send(if let Value::Exception(_, _) = rhs_value { rhs_value } else { Value::Boolean(rhs_value.is_truthy()) }) { return; }
+                            }
+                        } else {
+                            if !output.send(Value::Boolean(false)) { return; } // rhs is not evaluated
+                        }
+                    }
+                });
+            }
             AndThen { ref lhs, ref remaining_code } => {
                 // synchronously run the left-hand filter
                 let (lhs_input, mut input) = input.split();
-                let Receiver { context: lhs_ctxt, values: _ } = lhs_input.filter_sync(&lhs); // the values output by lhs are discarded
-                // parse the right-hand filter using the lhs output context
+                let Receiver { context: lhs_ctxt, values: lhs_values } = lhs_input.filter_sync(&lhs);
                 let lhs_ctxt = lhs_ctxt.await().expect("failed to get context of `;;` left operand");
+                // the values output by lhs are discarded, but an uncaught exception stops execution
+                if let Some(exception) = Values::new(lhs_values).find(|value| if let Value::Exception(_, _) = *value { true } else { false }) {
+                    let Sender { context, values } = output;
+                    context.complete(lhs_ctxt);
+                    values.send(exception);
+                    return;
+                }
+                // parse the right-hand filter using the lhs output context
                 let rhs = match parser::parse(remaining_code.clone(), lhs_ctxt.clone()) {
                     Ok(f) => f,
-                    Err(_) => {
+                    Err(err) => {
                         let Sender { context, values } = output;
                         context.complete(lhs_ctxt);
-                        values.send(Value::Exception(UString::from("syntax"), Object::default())); //TODO more useful metadata based on the error contents
+                        values.send(Value::exception("syntax", vec![("message", Value::String(UString::from(err.to_string())))]));
                         return;
                     }
                 };
@@ -46,14 +764,817 @@ impl Filter {
                 rhs_in_ctxt.complete(lhs_ctxt); // ...and its context from the output of lhs.
                 rhs.run(rhs_in_rx, output); // finally, rhs is run synchronously, with output directly into the `;;` filter's output.
             }
-            Custom { ref attributes, ref run } => {
+            Apply { ref function, ref args } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    apply(function, args, ctxt, value, output);
+                });
+            }
+            Array(ref inner) => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let array = value::Array::from(inner.run_value(ctxt.clone(), value)); //TODO send lazy arrays
+                    if let Some(exception) = array.iter().find(|item| if let Value::Exception(_, _) = **item { true } else { false }) {
+                        output.send(exception.clone());
+                        return;
+                    }
+                    output.send(Value::Array(array));
+                });
+            }
+            Assign { ref lhs, ref op, ref rhs } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let paths = match lhs.paths(ctxt, &value) {
+                        Ok(paths) => paths,
+                        Err(exception) => {
+                            output.send(exception);
+                            return;
+                        }
+                    };
+                    // the paths are evaluated on the input, then each output of rhs is assigned to all of them
+                    for rhs_value in rhs.run_value(ctxt.clone(), value.clone()) {
+                        if let Value::Exception(_, _) = rhs_value {
+                            output.send(rhs_value);
+                            return;
+                        }
+                        let mut result = value.clone();
+                        for path in &paths {
+                            let new_value = if let Some(ref op) = *op { op(result.getpath(path), rhs_value.clone()) } else { rhs_value.clone() };
+                            result = result.setpath(path, new_value);
+                            if let Value::Exception(_, _) = result {
+                                break;
+                            }
+                        }
+                        if !output.send(result) { return; }
+                    }
+                });
+            }
+            Bind { ref source, ref patterns, ref body } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    // all variables in all alternative patterns are bound, those not in the matching pattern to `null`
+                    let mut unmatched = Scope::default();
+                    for name in patterns.iter().flat_map(|pattern| pattern.variables()) {
+                        unmatched.insert(name, Value::Null);
+                    }
+                    for source_value in source.run_value(ctxt.clone(), value.clone()) {
+                        if let Value::Exception(_, _) = source_value {
+                            if !output.send(source_value) { return; }
+                            continue;
+                        }
+                        'patterns: for (idx, pattern) in patterns.iter().enumerate() {
+                            // with `?//`, an exception while destructuring or in the body tries the next pattern, except for the last one
+                            let is_last = idx + 1 == patterns.len();
+                            let scopes = match pattern.destructure(ctxt, &value, source_value.clone(), unmatched.clone()) {
+                                Ok(scopes) => scopes,
+                                Err(exception) => {
+                                    if is_last {
+                                        if !output.send(exception) { return; }
+                                    }
+                                    continue;
+                                }
+                            };
+                            for scope in scopes {
+                                for body_value in body.bind(&scope).run_value(ctxt.clone(), value.clone()) {
+                                    if let Value::Exception(ExceptionName::Raised(_), _) = body_value {
+                                        if !is_last { continue 'patterns; }
+                                    }
+                                    if !output.send(body_value) { return; }
+                                }
+                            }
+                            break;
+                        }
+                    }
+                });
+            }
+            BindGlobal { ref source, ref patterns } => {
+                let Receiver { context: in_ctxt, values } = input;
+                let Sender { context, values: out_values } = output;
+                let ctxt = in_ctxt.await().expect("failed to get input context");
+                let mut out_ctxt = ctxt.clone();
+                match bind_globals(&mut out_ctxt, source, patterns, Values::new(values)) {
+                    Ok(()) => { context.complete(out_ctxt); }
+                    Err(exception) => {
+                        // an exception leaves the context unchanged
+                        context.complete(ctxt);
+                        out_values.send(exception);
+                    }
+                }
+            }
+            Break(ref name) => {
+                // labels of enclosing `label` filters have already been replaced
+                for_each_value(input, output, |_, _, output| {
+                    output.send(Value::exception("undefinedLabel", vec![
+                        ("message", Value::String(UString::from(format!("${} is not a label", String::from(name))))),
+                        ("name", Value::String(name.clone()))
+                    ]));
+                });
+            }
+            Call { ref name, ref args } => {
+                // calls to defined functions have already been replaced by `Apply`, either by the parser or when binding an enclosing filter
+                for_each_value(input, output, |_, _, output| {
+                    output.send(Value::exception("undefinedFunction", vec![
+                        ("message", Value::String(UString::from(format!("{}/{} is not defined", String::from(name), args.len())))),
+                        ("name", Value::String(name.clone())),
+                        ("arity", Value::Number(BigRational::from_integer(BigInt::from_usize(args.len()).unwrap())))
+                    ]));
+                });
+            }
+            Comma { ref lhs, ref rhs } => {
+                // both operands run concurrently on the same input, but all of lhs's output is sent before rhs's
+                let (lhs_input, rhs_input) = input.split();
+                let Receiver { context: _, values: lhs_values } = lhs_input.filter(lhs); // the output context of lhs is discarded...
+                let Receiver { context: rhs_ctxt, values: rhs_values } = rhs_input.filter(rhs);
+                let Sender { context, values } = output;
+                context.complete(rhs_ctxt.await().expect("failed to get context of `,` right operand")); // ...and that of rhs is used, as if the operands ran in sequence.
+                for value in Values::new(lhs_values) {
+                    if !values.send(value) { return; }
+                }
+                for value in Values::new(rhs_values) {
+                    if !values.send(value) { return; }
+                }
+            }
+            Conditional { ref condition, ref then_branch, ref else_branch } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    for condition_value in condition.run_value(ctxt.clone(), value.clone()) {
+                        let branch = match condition_value {
+                            Value::Exception(_, _) => {
+                                if !output.send(condition_value) { return; }
+                                continue;
+                            }
+                            ref condition_value if condition_value.is_truthy() => then_branch,
+                            _ => else_branch
+                        };
+                        for branch_value in branch.run_value(ctxt.clone(), value.clone()) {
+                            if !output.send(branch_value) { return; }
+                        }
+                    }
+                });
+            }
+            Constant(ref v) => {
+                for_each_value(input, output, |_, _, output| {
+                    output.send(v.clone());
+                });
+            }
+            Custom { ref attributes, ref run, .. } => {
                 run(attributes, input, output)
             }
+            Def { ref function, ref rest, exported } => {
+                let mut scope = Scope::default();
+                scope.insert_function(function.clone());
+                let Receiver { context: in_ctxt, values } = input;
+                let mut ctxt = in_ctxt.await().expect("failed to get input context");
+                if exported {
+                    ctxt.functions.insert((function.name.clone(), function.arity()), function.clone());
+                }
+                let rest_input = Receiver {
+                    context: eventual::Future::of(ctxt),
+                    values: values
+                };
+                rest.bind(&scope).run(rest_input, output);
+            }
             Empty => {
                 let Receiver { context: in_ctxt, values: _ } = input;
                 let Sender { context, values: _ } = output;
                 context.complete(in_ctxt.await().expect("failed to get input context"));
             }
+            Foreach { ref source, ref pattern, ref init, ref update, ref extract } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    for state in init.run_value(ctxt.clone(), value.clone()) {
+                        if let Value::Exception(_, _) = state {
+                            output.send(state);
+                            return;
+                        }
+                        // the state is replaced by each output of update, and each of those is also extracted
+                        let mut state = state;
+                        for source_value in source.run_value(ctxt.clone(), value.clone()) {
+                            if let Value::Exception(_, _) = source_value {
+                                output.send(source_value);
+                                return;
+                            }
+                            let scopes = match pattern.destructure(ctxt, &value, source_value, Scope::default()) {
+                                Ok(scopes) => scopes,
+                                Err(exception) => {
+                                    output.send(exception);
+                                    return;
+                                }
+                            };
+                            for scope in scopes {
+                                let extract = extract.as_ref().map(|extract| extract.bind(&scope));
+                                for update_value in update.bind(&scope).run_value(ctxt.clone(), state.clone()) {
+                                    if let Value::Exception(_, _) = update_value {
+                                        output.send(update_value);
+                                        return;
+                                    }
+                                    state = update_value.clone();
+                                    if let Some(ref extract) = extract {
+                                        for extract_value in extract.run_value(ctxt.clone(), update_value) {
+                                            if !output.send(extract_value) { return; }
+                                        }
+                                    } else if !output.send(update_value) {
+                                        return;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+            Identity => {
+                let Receiver { context: in_ctxt, values } = input;
+                let Sender { context, values: out_values } = output;
+                context.complete(in_ctxt.await().expect("failed to get input context"));
+                for value in Values::new(values) {
+                    if !out_values.send(value) { return; }
+                }
+            }
+            Format(ref format) => {
+                for_each_value(input, output, |_, value, output| {
+                    output.send(format(value));
+                });
+            }
+            Index { ref lhs, ref index } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let lhs_values = lhs.run_value(ctxt.clone(), value.clone()).into_iter().collect::<Vec<_>>();
+                    for idx in index.run_value(ctxt.clone(), value) {
+                        for lhs_value in &lhs_values {
+                            if !output.send(lhs_value.index(&idx)) { return; }
+                        }
+                    }
+                });
+            }
+            Infix { ref lhs, ref op, ref rhs } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let lhs_values = lhs.run_value(ctxt.clone(), value.clone()).into_iter().collect::<Vec<_>>();
+                    for rhs_value in rhs.run_value(ctxt.clone(), value) {
+                        for lhs_value in &lhs_values {
+                            let result = match (lhs_value, &rhs_value) {
+                                (&Value::Exception(_, _), _) => lhs_value.clone(),
+                                (_, &Value::Exception(_, _)) => rhs_value.clone(),
+                                (_, _) => op(lhs_value.clone(), rhs_value.clone())
+                            };
+                            if !output.send(result) { return; }
+                        }
+                    }
+                });
+            }
+            Interpolation { ref parts, ref interpolations, ref format } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let format_value = |interpolated| match (interpolated, format.as_ref()) {
+                        (Value::Exception(name, meta), _) => Err(Value::Exception(name, meta)),
+                        (Value::String(s), None) => Ok(String::from(s)),
+                        (interpolated, None) => Ok(interpolated.to_json()),
+                        (interpolated, Some(format)) => match format(interpolated) {
+                            Value::String(s) => Ok(String::from(s)),
+                            Value::Exception(name, meta) => Err(Value::Exception(name, meta)),
+                            formatted => Err(Value::type_error(format!("a format must return a string, not {}", formatted.type_name())))
+                        }
+                    };
+                    let (first, rest) = match interpolations.split_first() {
+                        Some(split) => split,
+                        None => {
+                            output.send(Value::String(parts[0].clone()));
+                            return;
+                        }
+                    };
+                    // the outputs of the interpolations are combined like the operands of an infix operator, with the first interpolation varying fastest, so only the outputs of the others are collected
+                    let mut formatted = vec![];
+                    for interpolation in rest {
+                        let mut strings = vec![];
+                        for interpolated in interpolation.run_value(ctxt.clone(), value.clone()) {
+                            match format_value(interpolated) {
+                                Ok(s) => { strings.push(s); }
+                                Err(exception) => {
+                                    output.send(exception);
+                                    return;
+                                }
+                            }
+                        }
+                        if strings.is_empty() { return; }
+                        formatted.push(strings);
+                    }
+                    let mut indices = vec![0; formatted.len()];
+                    loop {
+                        let mut suffix = String::from(&parts[1]);
+                        for (i, strings) in formatted.iter().enumerate() {
+                            suffix.push_str(&strings[indices[i]]);
+                            suffix.push_str(&String::from(&parts[i + 2]));
+                        }
+                        // the first interpolation runs again for each combination of the others, and its outputs are sent as they are produced
+                        for interpolated in first.run_value(ctxt.clone(), value.clone()) {
+                            match format_value(interpolated) {
+                                Ok(s) => {
+                                    if !output.send(Value::String(UString::from(format!("{}{}{}", String::from(&parts[0]), s, suffix)))) { return; }
+                                }
+                                Err(exception) => {
+                                    output.send(exception);
+                                    return;
+                                }
+                            }
+                        }
+                        // advance to the next combination, or stop after the last one
+                        let mut i = 0;
+                        loop {
+                            if i == indices.len() { return; }
+                            indices[i] += 1;
+                            if indices[i] < formatted[i].len() { break; }
+                            indices[i] = 0;
+                            i += 1;
+                        }
+                    }
+                });
+            }
+            Iterate(ref lhs) => {
+                let Receiver { context: lhs_ctxt, values: lhs_values } = input.filter(lhs);
+                let Sender { context, values } = output;
+                context.complete(lhs_ctxt.await().expect("failed to get context of iterated filter"));
+                for lhs_value in Values::new(lhs_values) {
+                    match lhs_value {
+                        Value::Array(array) => {
+                            for item in array {
+                                if !values.send(item) { return; }
+                            }
+                        }
+                        Value::Object(object) => {
+                            for (_, item) in object {
+                                if !values.send(item) { return; }
+                            }
+                        }
+                        Value::Exception(_, _) => {
+                            if !values.send(lhs_value) { return; }
+                        }
+                        _ => {
+                            if !values.send(Value::type_error(format!("cannot iterate over {}", lhs_value.type_name()))) { return; }
+                        }
+                    }
+                }
+            }
+            Label { ref name, ref body } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let id = NEXT_LABEL_ID.fetch_add(1, Ordering::SeqCst);
+                    let mut scope = Scope::default();
+                    scope.insert_label(name.clone(), id);
+                    for body_value in body.bind(&scope).run_value(ctxt.clone(), value) {
+                        if let Value::Exception(ExceptionName::Break(break_id), _) = body_value {
+                            if break_id == id {
+                                // dropping the rest of the body's output stops it
+                                return;
+                            }
+                        }
+                        if !output.send(body_value) { return; }
+                    }
+                });
+            }
+            Negate(ref operand) => {
+                let Receiver { context: operand_ctxt, values: operand_values } = input.filter(operand);
+                let Sender { context, values } = output;
+                context.complete(operand_ctxt.await().expect("failed to get context of negated filter"));
+                for value in Values::new(operand_values) {
+                    let result = match value {
+                        Value::Exception(_, _) => value,
+                        Value::Number(n) => Value::Number(-n),
+                        _ => Value::type_error(format!("cannot negate {}", value.type_name()))
+                    };
+                    if !values.send(result) { return; }
+                }
+            }
+            Object(ref entries) => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let mut objects = vec![value::Object::default()];
+                    for &(ref key_filter, ref value_filter) in entries {
+                        let mut pairs = vec![];
+                        for key in key_filter.run_value(ctxt.clone(), value.clone()) {
+                            if let Value::Exception(_, _) = key {
+                                output.send(key);
+                                return;
+                            }
+                            let key = match key {
+                                Value::Array(_) | Value::Object(_) | Value::Function(_) => {
+                                    output.send(Value::type_error(format!("cannot use {} as an object key", key.type_name())));
+                                    return;
+                                }
+                                key => key.to_hashable().expect("scalar values are hashable")
+                            };
+                            for val in value_filter.run_value(ctxt.clone(), value.clone()) {
+                                if let Value::Exception(_, _) = val {
+                                    output.send(val);
+                                    return;
+                                }
+                                pairs.push((key.clone(), val));
+                            }
+                        }
+                        // each output of each entry produces a separate object, with earlier entries varying slowest
+                        objects = objects.iter().flat_map(|object| pairs.iter().map(move |&(ref k, ref v)| {
+                            let mut object = object.clone();
+                            object.insert(k.clone(), v.clone());
+                            object
+                        })).collect();
+                    }
+                    for object in objects {
+                        if !output.send(Value::Object(object)) { return; }
+                    }
+                });
+            }
+            Or { ref lhs, ref rhs } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    for lhs_value in lhs.run_value(ctxt.clone(), value.clone()) {
+                        if let Value::Exception(_, _) = lhs_value {
+                            if !output.send(lhs_value) { return; }
+                        } else if lhs_value.is_truthy() {
+                            if !output.send(Value::Boolean(true)) { return; } // rhs is not evaluated
+                        } else {
+                            for rhs_value in rhs.run_value(ctxt.clone(), value.clone()) {
+                                if !output.send(if let Value::Exception(_, _) = rhs_value { rhs_value } else { Value::Boolean(rhs_value.is_truthy()) }) { return; }
+                            }
+                        }
+                    }
+                });
+            }
+            Pipe { ref lhs, ref rhs } => {
+                // lhs runs asynchronously, so its output is streamed into rhs as it is produced
+                let Receiver { context: lhs_ctxt, values: lhs_values } = input.filter(lhs);
+                let (Sender { context: rhs_in_ctxt, values: rhs_in_values }, rhs_input) = channel();
+                let (exception_tx, exception_rx) = eventual::Future::<Option<Value>, ()>::pair();
+                thread::spawn(move || {
+                    rhs_in_ctxt.complete(lhs_ctxt.await().expect("failed to get context of `|` left operand"));
+                    for value in Values::new(lhs_values) {
+                        if let Value::Exception(_, _) = value {
+                            // an exception from lhs is not passed to rhs, but ends its input...
+                            exception_tx.complete(Some(value));
+                            return;
+                        }
+                        if !rhs_in_values.send(value) { break; } // rhs needs no more input, so the rest of lhs is not raised
+                    }
+                    exception_tx.complete(None);
+                });
+                let Receiver { context: rhs_ctxt, values: rhs_values } = rhs_input.filter(rhs);
+                let Sender { context, values } = output;
+                context.complete(rhs_ctxt.await().expect("failed to get context of `|` right operand"));
+                for value in Values::new(rhs_values) {
+                    if let Value::Exception(_, _) = value {
+                        values.send(value); // an exception from rhs ends the output
+                        return;
+                    }
+                    if !values.send(value) { return; }
+                }
+                // ...and is raised once rhs has processed the values before it.
+                if let Some(exception) = exception_rx.await().expect("failed to get exception of `|` left operand") {
+                    values.send(exception);
+                }
+            }
+            Slice { ref lhs, ref from, ref to } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let bound_values = |bound: &Option<Box<Filter>>| if let Some(ref f) = *bound {
+                        f.run_value(ctxt.clone(), value.clone()).into_iter().collect::<Vec<_>>()
+                    } else {
+                        vec![Value::Null]
+                    };
+                    let from_values = bound_values(from);
+                    let to_values = bound_values(to);
+                    let lhs_values = lhs.run_value(ctxt.clone(), value.clone()).into_iter().collect::<Vec<_>>();
+                    for to_value in &to_values {
+                        for from_value in &from_values {
+                            for lhs_value in &lhs_values {
+                                if !output.send(lhs_value.slice(from_value, to_value)) { return; }
+                            }
+                        }
+                    }
+                });
+            }
+            Raise { ref name, ref meta } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    for name_value in name.run_value(ctxt.clone(), value.clone()) {
+                        for meta_value in meta.run_value(ctxt.clone(), value.clone()) {
+                            let exception = match (name_value.clone(), meta_value) {
+                                (Value::Exception(name, meta), _) | (_, Value::Exception(name, meta)) => Value::Exception(name, meta),
+                                (Value::String(name), Value::Object(meta)) => Value::Exception(ExceptionName::Raised(name), meta),
+                                (Value::String(_), meta_value) => Value::type_error(format!("exception metadata must be an object, not {}", meta_value.type_name())),
+                                (name_value, _) => Value::type_error(format!("exception name must be a string, not {}", name_value.type_name()))
+                            };
+                            if !output.send(exception) { return; }
+                        }
+                    }
+                });
+            }
+            Reduce { ref source, ref pattern, ref init, ref update } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    for state in init.run_value(ctxt.clone(), value.clone()) {
+                        if let Value::Exception(_, _) = state {
+                            output.send(state);
+                            return;
+                        }
+                        let mut state = state;
+                        for source_value in source.run_value(ctxt.clone(), value.clone()) {
+                            if let Value::Exception(_, _) = source_value {
+                                output.send(source_value);
+                                return;
+                            }
+                            let scopes = match pattern.destructure(ctxt, &value, source_value, Scope::default()) {
+                                Ok(scopes) => scopes,
+                                Err(exception) => {
+                                    output.send(exception);
+                                    return;
+                                }
+                            };
+                            for scope in scopes {
+                                // the new state is the last output of update, or `null` if there is none
+                                let mut new_state = Value::Null;
+                                for update_value in update.bind(&scope).run_value(ctxt.clone(), state) {
+                                    if let Value::Exception(_, _) = update_value {
+                                        output.send(update_value);
+                                        return;
+                                    }
+                                    new_state = update_value;
+                                }
+                                state = new_state;
+                            }
+                        }
+                        if !output.send(state) { return; }
+                    }
+                });
+            }
+            Try { ref body, ref handler } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    for body_value in body.run_value(ctxt.clone(), value.clone()) {
+                        match body_value {
+                            Value::Exception(ExceptionName::Raised(name), meta) => {
+                                // the exception ends the output of the body, and is replaced with the output of the handler, if any
+                                if let Some(ref handler) = *handler {
+                                    let caught = Value::Object(vec![
+                                        (HashableValue::String(UString::from("name")), Value::String(name)),
+                                        (HashableValue::String(UString::from("metadata")), Value::Object(meta))
+                                    ].into_iter().collect());
+                                    for handler_value in handler.run_value(ctxt.clone(), caught) {
+                                        if !output.send(handler_value) { return; }
+                                    }
+                                }
+                                break;
+                            }
+                            body_value => if !output.send(body_value) { return; }
+                        }
+                    }
+                });
+            }
+            Update { ref lhs, ref rhs } => {
+                for_each_value(input, output, |ctxt, value, output| {
+                    let paths = match lhs.paths(ctxt, &value) {
+                        Ok(paths) => paths,
+                        Err(exception) => {
+                            output.send(exception);
+                            return;
+                        }
+                    };
+                    // each value is replaced with the first output of rhs, or deleted if there is none
+                    let mut result = value;
+                    let mut deleted = vec![];
+                    for path in paths {
+                        match rhs.run_value(ctxt.clone(), result.getpath(&path)).into_iter().next() {
+                            Some(Value::Exception(name, meta)) => {
+                                output.send(Value::Exception(name, meta));
+                                return;
+                            }
+                            Some(new_value) => { result = result.setpath(&path, new_value); }
+                            None => { deleted.push(path); }
+                        }
+                        if let Value::Exception(_, _) = result {
+                            break;
+                        }
+                    }
+                    output.send(result.delpaths(deleted));
+                });
+            }
+            Variable(ref name) => {
+                // variables bound with `as` have already been replaced by their values, so the variable is looked up in the context
+                for_each_value(input, output, |ctxt, _, output| {
+                    if let Some(value) = ctxt.variables.get(name) {
+                        output.send(value.clone());
+                        return;
+                    }
+                    output.send(Value::exception("undefinedVariable", vec![
+                        ("message", Value::String(UString::from(format!("${} is not defined", String::from(name))))),
+                        ("name", Value::String(name.clone()))
+                    ]));
+                });
+            }
         }
     }
 }
+
+#[test]
+fn test_array() {
+    use util::run;
+
+    assert_eq!(run("[], [1, (2, 3)], [(1, 2) | [., .]]"), vec!["[]", "[1, 2, 3]", "[[1, 1], [2, 2]]"]);
+    // an exception replaces the whole array
+    assert_eq!(run("[1, (1 | .foo), 2]"), vec![r#"raise "type" {"message": "cannot index number with string"}"#]);
+}
+
+#[test]
+fn test_bind() {
+    use util::run;
+
+    assert_eq!(run(r#"[1, {"b": 2, "c": [3]}] as [$a, {b: $c, c: [$d]}] | [$a, $c, $d]"#), vec!["[1, 2, 3]"]);
+    assert_eq!(run("(1, 2) as $x | [$x, .]"), vec!["[1, null]", "[2, null]"]);
+    // a binding is only visible in its body
+    assert_eq!(run("1 as $x | (2 as $x | $x), $x"), vec!["2", "1"]);
+    // with `?//`, an exception while destructuring or in the body tries the next pattern
+    assert_eq!(run("[[1], 2] | [.[] as [$x] ?// $x | $x]"), vec!["[1, 2]"]);
+    assert_eq!(run(r#"[[1], 2] | [.[] as [$x] ?// $x | if $x == 1 then raise "foo" else $x end]"#), vec!["[[1], 2]"]);
+    // variables of the other patterns are `null`
+    assert_eq!(run("[1] as [$a] ?// $b | [$a, $b]"), vec!["[1, null]"]);
+}
+
+#[test]
+fn test_comma() {
+    use std::sync::Arc;
+
+    use builtin;
+    use util::Labeled;
+
+    let without_operators = Filter::Custom {
+        attributes: vec![],
+        run: Box::new(Labeled::new("<clear operators>", Arc::new(|_, input, output| {
+            let Receiver { context: in_ctxt, values } = input;
+            let Sender { context, values: out_values } = output;
+            let mut ctxt = in_ctxt.await().expect("failed to get input context");
+            ctxt.operators.clear();
+            context.complete(ctxt);
+            for value in values {
+                out_values.send(value);
+            }
+        }))),
+        paths: None
+    };
+    let array = |items: Vec<&str>| Value::Array(items.into_iter().map(|s| Value::String(UString::from(s))).collect());
+    let run = |f: Filter| {
+        let Receiver { context, values } = Receiver::from_values(builtin::context(), vec![array(vec!["a", "b"]), array(vec!["c", "d"])]).filter(&f);
+        let output = values.into_iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        (context.await().unwrap().operators.len(), output)
+    };
+    // all outputs of lhs come before all outputs of rhs
+    let f = parser::parse(".[0], .[1]", builtin::context()).unwrap();
+    assert_eq!(run(f).1, vec!["\"a\"", "\"c\"", "\"b\"", "\"d\""]);
+    // the output context is that of rhs
+    let f = Filter::Comma { lhs: Box::new(without_operators.clone()), rhs: Box::new(Filter::Identity) };
+    assert_eq!(run(f), (builtin::context().operators.len(), vec![array(vec!["a", "b"]).to_string(), array(vec!["c", "d"]).to_string(), array(vec!["a", "b"]).to_string(), array(vec!["c", "d"]).to_string()]));
+    let f = Filter::Comma { lhs: Box::new(Filter::Identity), rhs: Box::new(without_operators) };
+    assert_eq!(run(f).0, 0);
+}
+
+#[test]
+fn test_conditional() {
+    use util::run;
+
+    assert_eq!(run(r#"[(0, 1, 2, 3) | if . == 0 then "a" elif . == 1 then "b" elif . == 2 then "c" else "d" end]"#), vec![r#"["a", "b", "c", "d"]"#]);
+    // only `false` and `null` are falsy
+    assert_eq!(run(r#"[1, null, false, 0, ""] | [.[] | if . then "t" else "f" end]"#), vec![r#"["t", "f", "f", "t", "t"]"#]);
+    // a missing `else` outputs the input
+    assert_eq!(run(r#"[(1, 2) | if . == 1 then "one" end], [if false then 1 elif false then 2 end]"#), vec![r#"["one", 2]"#, "[null]"]);
+    // each output of the condition selects a branch
+    assert_eq!(run("[if (true, false) then 1 else 2 end]"), vec!["[1, 2]"]);
+}
+
+#[test]
+fn test_exceptions() {
+    use util::run;
+
+    // an uncaught exception ends the stream
+    assert_eq!(run("1, raise \"foo\" {\"bar\": 2}, 3"), vec!["1", "raise \"foo\" {\"bar\": 2}"]);
+    // a caught exception is replaced with the output of the handler
+    assert_eq!(run("try (1, raise \"foo\", 3) catch .name"), vec!["1", "\"foo\""]);
+    assert_eq!(run("try (1, raise \"foo\", 3)"), vec!["1"]);
+    // an exception from the lhs of a pipe is raised after rhs has processed the values before it
+    assert_eq!(run("(1, raise \"foo\", 3) | (., .)"), vec!["1", "1", "raise \"foo\""]);
+    // `;;` stops at an uncaught exception
+    assert_eq!(run("raise \"foo\" ;; 1"), vec!["raise \"foo\""]);
+    // `break` ends the output of its label, and is not caught by `try`
+    assert_eq!(run("[label $out | 1, break $out, 2]"), vec!["[1]"]);
+    assert_eq!(run("[label $out | try (1, break $out, 2) catch 3]"), vec!["[1]"]);
+    assert_eq!(run("[label $out | repeat(1) | if . == 1 then break $out else . end]"), vec!["[null]"]);
+    // an exception named "break" can't stand in for `break`
+    assert_eq!(run("[label $out | try (1, raise \"break\" {id: 0}, 2) catch .name]"), vec!["[1, \"break\"]"]);
+    assert_eq!(run("[label $out | (raise \"break\" {id: 0}) // 1]"), vec!["[1]"]);
+    // `//` suppresses exceptions from its lhs, except for `break`
+    assert_eq!(run("[(null, 1, false, 2) // 3], [(false, raise \"foo\") // 3, 4]"), vec!["[1, 2]", "[3, 4]"]);
+    assert_eq!(run("[label $out | (break $out) // 1]"), vec!["[]"]);
+}
+
+#[test]
+fn test_functions() {
+    use builtin;
+    use util::run;
+
+    // recursion
+    assert_eq!(run("def fac: if . <= 1 then 1 else . * (. - 1 | fac) end; 5 | fac"), vec!["120"]);
+    // filter and value parameters
+    assert_eq!(run("def f(g; $n): [g, $n]; f(1, 2; 3)"), vec!["[1, 2, 3]"]);
+    // functions close over the variables and functions of their definition
+    assert_eq!(run("1 as $x | def f: $x; 2 as $x | f"), vec!["1"]);
+    assert_eq!(run("def f: 1; def g: f; def f: 2; [g, f]"), vec!["[1, 2]"]);
+    assert_eq!(run("def f(x): def g: x; g; f(4)"), vec!["4"]);
+    // a function definition in an argument doesn't end it
+    assert_eq!(run("[1, 2] | map(def f: . + 1; f)"), vec!["[2, 3]"]);
+    // definitions remain available after `;;`, and can be replaced there
+    assert_eq!(run("def f: 1;; def g(x): [x, f]; ;; g(2)"), vec!["[2, 1]"]);
+    assert_eq!(run("def f: 1;; def f: 2;; f"), vec!["2"]);
+    // only top-level definitions are exported, not those in function bodies or parens
+    let f = parser::parse("def g: def h: 1; h; (def i: 2; i) | g", builtin::context()).unwrap();
+    let ctxt = Receiver::from_values(builtin::context(), vec![Value::Null]).filter(&f).context.await().unwrap();
+    assert!(ctxt.functions.contains_key(&(UString::from("g"), 0)));
+    assert!(!ctxt.functions.contains_key(&(UString::from("h"), 0)));
+    assert!(!ctxt.functions.contains_key(&(UString::from("i"), 0)));
+}
+
+#[test]
+fn test_globals() {
+    use builtin;
+    use util::run;
+
+    // a binding without a body binds its variables for the code after `;;`
+    assert_eq!(run("1 as $x ;; $x, $x + 1"), vec!["1", "2"]);
+    assert_eq!(run("[1, [2]] as [$a, [$b]] ;; 3 as $a ;; [$a, $b]"), vec!["[3, 2]"]);
+    assert_eq!(run("1 as $x ;; 2 as $x | $x"), vec!["2"]);
+    // an exception while binding stops execution
+    assert_eq!(run("(1, raise \"foo\") as $x ;; $x"), vec!["raise \"foo\""]);
+    // the variables are also in the output context, which the REPL passes on to the next line
+    let f = parser::parse("\"a\" as $x", builtin::context()).unwrap();
+    let ctxt = Receiver::from_values(builtin::context(), vec![Value::Null]).filter(&f).context.await().unwrap();
+    assert_eq!(ctxt.variables.get(&UString::from("x")), Some(&Value::String(UString::from("a"))));
+}
+
+#[test]
+fn test_iterate() {
+    use util::run;
+
+    assert_eq!(run(r#"[[1, 2], {"a": 3, "b": 4}] | [.[] | .[]]"#), vec!["[1, 2, 3, 4]"]);
+    assert_eq!(run("1 | .[]"), vec![r#"raise "type" {"message": "cannot iterate over number"}"#]);
+    // `?` suppresses exceptions, but the stream still ends at the first one
+    assert_eq!(run(r#"[1, "a"] | [.[] | .[]?], [.[]?], [(1, (1 | .foo), 2)?]"#), vec!["[]", r#"[1, "a"]"#, "[1]"]);
+}
+
+#[test]
+fn test_object() {
+    use std::sync::Arc;
+
+    use util::{run, run_with_input};
+
+    assert_eq!(run(r#"{"x": 1, "k": "c", "v": 2, "foo": 3} | 4 as $var | {a: .x, "b": 1, (.k): .v, $var, foo}"#), vec![r#"{"a": 1, "b": 1, "c": 2, "var": 4, "foo": 3}"#]);
+    // each combination of the outputs of the entries produces an object, with earlier entries varying slowest
+    assert_eq!(run("[{a: (1, 2), b: (3, 4)}]"), vec![r#"[{"a": 1, "b": 3}, {"a": 1, "b": 4}, {"a": 2, "b": 3}, {"a": 2, "b": 4}]"#]);
+    // the keywords `null`, `true`, and `false` are names of keys like other identifiers
+    assert_eq!(run(r#"{"true": 2} | {null: 1, true}"#), vec![r#"{"null": 1, "true": 2}"#]);
+    // arrays, objects, and functions can't be keys
+    assert_eq!(run("{([1]): 1}"), vec![r#"raise "type" {"message": "cannot use array as an object key"}"#]);
+    assert_eq!(run("{({}): 1}"), vec![r#"raise "type" {"message": "cannot use object as an object key"}"#]);
+    let function = Value::Function(value::Function { name: UString::from("f"), params: vec![], body: Arc::new(Filter::Empty) });
+    assert_eq!(run_with_input("{(.): 1}", function), vec![r#"raise "type" {"message": "cannot use function as an object key"}"#]);
+}
+
+#[test]
+fn test_pipe() {
+    use num::{BigRational, FromPrimitive};
+
+    use builtin;
+    use lang::value::Array;
+    use util::run_with_input;
+
+    let number = |n| Value::Number(BigRational::from_integer(FromPrimitive::from_i32(n).unwrap()));
+    let array = |items: Vec<Value>| Value::Array(Array::from(items));
+    assert_eq!(run_with_input(".[1] | .[0]", array(vec![number(1), array(vec![number(2)])])), vec!["2"]);
+    // each input is streamed through both sides, and an exception ends the stream
+    let f = parser::parse(".[0] | .[1]", builtin::context()).unwrap();
+    let inputs = vec![array(vec![array(vec![number(1), number(2)])]), array(vec![number(3)]), array(vec![array(vec![number(4), number(5)])])];
+    let output = Receiver::from_values(builtin::context(), inputs).filter(&f).into_iter().map(|v| v.to_string()).collect::<Vec<_>>();
+    assert_eq!(output, vec!["2", "raise \"type\" {\"message\": \"cannot index number with number\"}"]);
+    assert_eq!(run_with_input(".foo | .[0]", array(vec![])), vec!["raise \"type\" {\"message\": \"cannot index array with string\"}"]);
+}
+
+#[test]
+fn test_reduce() {
+    use builtin;
+    use util::run;
+
+    assert_eq!(run("reduce (1, 2, 3) as $x (0; . + $x)"), vec!["6"]);
+    assert_eq!(run("[foreach ([1, 2], [3, 4]) as [$a, $b] (0; . + $a; [$b, .])]"), vec!["[[2, 1], [4, 4]]"]);
+    // a function definition in an argument doesn't end it
+    assert_eq!(run("reduce (1, 2) as $x (0; def f: 1; . + f + $x)"), vec!["5"]);
+    assert_eq!(run("[foreach (1, 2) as $x (def g: 0; g; def h: def i: .; i; [h, $x])]"), vec!["[[0, 1], [[0, 1], 2]]"]);
+    assert_eq!(run("(def f: 1; f)"), vec!["1"]);
+    // infinite generators stop once their consumer has enough values
+    assert_eq!(run("[limit(3; repeat(1))]"), vec!["[null, 1, 1]"]);
+    assert_eq!(run("first(range(3; 1000000000) | . * 2)"), vec!["6"]);
+    // a pipe whose rhs stops reading its input early, run on this thread so that a panic fails the test
+    let f = parser::parse("range(1000000000) | ()", builtin::context()).unwrap();
+    assert_eq!(Receiver::from_values(builtin::context(), vec![Value::Null]).filter_sync(&f).into_iter().count(), 0);
+}
+
+#[test]
+fn test_paths() {
+    use util::run;
+
+    assert_eq!(run("[path(.a[0].b, first(.[1:], .[]))]"), vec![r#"[["a", 0, "b"], [{"start": 1, "end": null}]]"#]);
+    assert_eq!(run("[[1, 2, 3] | .[] += 1, .[1:] = [\"x\"], .[] |= ()]"), vec![r#"[[2, 3, 4], [1, "x"], []]"#]);
+    assert_eq!(run(".a.b = 1 | .a.b |= . + 1 | del(.c)"), vec![r#"{"a": {"b": 2}}"#]);
+    assert_eq!(run(r#"{"a": [1, [2, 3]]} | getpath(["a", -1, 0]), getpath(["a", {"start": 1, "end": null}, 0, 1]), getpath(["b", "c"]), getpath([])"#), vec!["2", "3", "null", r#"{"a": [1, [2, 3]]}"#]);
+    assert_eq!(run("{\"a\": [1]} | [paths], to_entries"), vec![r#"[["a"], ["a", 0]]"#, r#"[{"key": "a", "value": [1]}]"#]);
+    assert_eq!(run("{\"a\": [1]} | [..], [path(..)]"), vec![r#"[{"a": [1]}, [1], 1]"#, r#"[[], ["a"], ["a", 0]]"#]);
+    assert_eq!(run("[1, [2, 3]] | walk(if . == 2 then () else [.] end)"), vec!["[[[1], [[[3]]]]]"]);
+    assert_eq!(run("[try (.[1e12] = 1) catch .metadata.message, try setpath([1e12]; 1) catch .metadata.message]"), vec![r#"["array index too large", "array index too large"]"#]);
+    assert_eq!(run("1 | path(. + 1)"), vec![r#"raise "invalidPath" {"message": "invalid path expression with result 2", "value": 2}"#]);
+}
@@ -2,6 +2,7 @@ pub mod channel;
 pub mod context;
 pub mod filter;
 pub mod parser;
+pub mod scope;
 pub mod value;
 
 pub use self::context::Context;
@@ -1,31 +1,170 @@
-use std::{fmt, mem};
+use std::{cmp, fmt, iter, mem};
 use std::sync::{Arc, Mutex};
 
 use itertools::{Itertools, MultiPeek};
 
+use num::{self, BigInt, BigRational, FromPrimitive};
+
 use unicode::{self, UString};
 
-use lang::{Context, Filter};
+use lang::{Context, Filter, Value};
+use lang::filter::Pattern;
+use lang::value::Function;
+use lang::value::function::Param;
+use lang::value::Object;
 use lang::context::PrecedenceGroup;
-use util::Labeled;
+use util::{FormatFn, Labeled};
 
+/// The kinds of errors which can occur while parsing.
 #[derive(Debug)]
-pub enum ParseError {
+pub enum ParseErrorKind {
+    InvalidPattern(Filter),
     InvalidToken(char),
     MismatchedParens(Token, Tf),
+    MissingKeyword(&'static str),
+    MissingOperand(Token),
     NotAllowed(Filter),
     NotFullyParsed(Vec<Tf>),
-    UnbalancedParen(Token)
+    UnbalancedParen(Token),
+    /// A format like `@foo` which is not defined in the context
+    UnknownFormat(UString),
+    /// A call to a function which is not defined in the context or by an enclosing `def`, by name and arity
+    UnknownFunction(UString, usize)
+}
+
+impl ParseErrorKind {
+    /// Returns an error of this kind, caused by the given span of code.
+    fn at(self, span: Span) -> ParseError {
+        ParseError {
+            kind: self,
+            span: span,
+            source: UString::from("")
+        }
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseErrorKind::InvalidPattern(_) => write!(w, "invalid pattern"),
+            ParseErrorKind::InvalidToken(c) => write!(w, "unexpected character {:?}", c),
+            ParseErrorKind::MismatchedParens(_, _) => write!(w, "mismatched parentheses"),
+            ParseErrorKind::MissingKeyword(keyword) => write!(w, "missing `{}`", keyword),
+            ParseErrorKind::MissingOperand(_) => write!(w, "missing operand"),
+            ParseErrorKind::NotAllowed(_) => write!(w, "filter not allowed in this context"),
+            ParseErrorKind::NotFullyParsed(_) => write!(w, "unexpected code"),
+            ParseErrorKind::UnbalancedParen(_) => write!(w, "unbalanced parenthesis"),
+            ParseErrorKind::UnknownFormat(ref name) => write!(w, "@{} is not defined", String::from(name)),
+            ParseErrorKind::UnknownFunction(ref name, arity) => write!(w, "{}/{} is not defined", String::from(name), arity)
+        }
+    }
+}
+
+/// An error which occurred while parsing, along with the part of the code which caused it.
+#[derive(Debug)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+    /// All of the code passed to `parse`, for showing the line containing the error
+    source: UString
+}
+
+impl fmt::Display for ParseError {
+    /// Shows the message and position of the error, followed by the line of code where the error starts, with the span underlined by carets.
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        let Span { start, end } = self.span;
+        try!(write!(w, "{} at line {}, column {}", self.kind, start.line + 1, start.column + 1));
+        if let Some(line) = String::from(&self.source).lines().nth(start.line) {
+            let line_len = line.chars().count();
+            let end_column = if end.line == start.line { cmp::min(end.column, line_len) } else { line_len };
+            let indent = line.chars().take(start.column).map(|c| if c == '\t' { '\t' } else { ' ' }).collect::<String>();
+            let underline = iter::repeat('^').take(cmp::max(end_column.saturating_sub(start.column), 1)).collect::<String>();
+            try!(write!(w, "\n{}\n{}{}", line, indent, underline));
+        }
+        Ok(())
+    }
+}
+
+/// A position in the code. All fields except `byte_offset` count code points, starting at 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Position {
+    /// The number of characters before this position
+    pub offset: usize,
+    /// The length in bytes of the UTF-8 encoded code before this position
+    pub byte_offset: usize,
+    /// The number of newlines before this position
+    pub line: usize,
+    /// The number of characters between the start of the line and this position
+    pub column: usize
+}
+
+/// A part of the code, from `start` up to but excluding `end`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position
+}
+
+impl Span {
+    /// Returns the span from the start of this span to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Token {
     /// An unrecognized character
     Invalid(char),
+    /// The identity filter `.`
+    Dot,
+    /// The recursive descent `..`, short for `recurse`
+    DotDot,
+    /// A field access like `.foo`
+    Field(UString),
+    /// A literal value like `"foo"`, `1.5e-3` or `true`. Negative numbers are parsed using the `-` operator.
+    Literal(Value),
+    /// A string literal with interpolations like `"foo \\(.bar) baz"`: the literal parts, and the tokens of each interpolated filter between them, with their spans
+    InterpolatedString(Vec<UString>, Vec<Vec<(Token, Span)>>),
+    /// A format like `@base64`
+    Format(UString),
     /// An opening parenthesis `(`
     OpenParen,
     /// A closing parenthesis `)`
     CloseParen,
+    /// An opening bracket `[`
+    OpenBracket,
+    /// A closing bracket `]`
+    CloseBracket,
+    /// A pair of brackets and the unparsed tokens between them, produced from `OpenBracket` and `CloseBracket` by the parser. Depending on what precedes them, they construct an array or index into a value.
+    Brackets(Vec<Tf>),
+    /// Parenthesized filters separated by `;` which are not the arguments of a function call, produced by the parser. These are the `(INIT; UPDATE)` of `reduce` and `foreach`.
+    Arguments(Vec<Filter>),
+    /// An opening brace `{`
+    OpenBrace,
+    /// A closing brace `}`
+    CloseBrace,
+    /// A bare word which is not a keyword, like the `foo` in `{foo: 1}`, optionally qualified with a module name like `foo::bar`
+    Ident(UString),
+    /// A variable reference like `$foo`
+    Variable(UString),
+    /// The slice and object entry separator `:`
+    Colon,
+    /// The error suppression operator `?`
+    Question,
+    /// The destructuring alternative operator `?//`
+    DestructuringAlternative,
+    /// An operator registered in the context, like `+`
+    Operator(String),
+    /// The pipe operator `|`
+    Pipe,
+    /// The comma operator `,`
+    Comma,
+    /// The separator `;`, which ends function definitions and separates arguments
+    Semicolon,
     /// The sequential execution operator `;;`, and all following code
     AndThen(Code),
     /// A sequence of one or more whitespace characters
@@ -53,11 +192,17 @@ impl fmt::Debug for CodeVariant { // https://github.com/bluss/rust-itertools/iss
 }
 
 #[derive(Debug)]
-pub struct Code(Mutex<CodeVariant>);
+pub struct Code {
+    variant: Mutex<CodeVariant>,
+    /// All of the code, including the part which has already been read, for showing the context of parse errors
+    source: UString,
+    /// The position of the next character
+    position: Position
+}
 
 impl Code {
     fn peek(&mut self) -> Option<char> {
-        let mut lock = self.0.lock().unwrap();
+        let mut lock = self.variant.lock().unwrap();
         match *&mut *lock {
             CodeVariant::Empty => None,
             CodeVariant::UString { ref s, ref mut peek_index } => {
@@ -78,32 +223,46 @@ impl Code {
 
 impl Default for Code {
     fn default() -> Code {
-        Code(Mutex::new(CodeVariant::Empty))
+        Code {
+            variant: Mutex::new(CodeVariant::Empty),
+            source: UString::from(""),
+            position: Position::default()
+        }
     }
 }
 
 impl<T: Into<UString>> From<T> for Code {
     fn from(code_string: T) -> Code {
-        Code(Mutex::new(CodeVariant::UString { s: code_string.into(), peek_index: 0 }))
+        let s = code_string.into();
+        Code {
+            variant: Mutex::new(CodeVariant::UString { s: s.clone(), peek_index: 0 }),
+            source: s,
+            position: Position::default()
+        }
     }
 }
 
 impl Clone for Code {
     fn clone(&self) -> Code {
-        let mut lock = self.0.lock().unwrap();
-        match *&mut *lock {
-            CodeVariant::Empty => Code(Mutex::new(CodeVariant::Empty)),
-            CodeVariant::UString { ref s, .. } => Code(Mutex::new(CodeVariant::UString { s: s.clone(), peek_index: 0 })),
+        let mut lock = self.variant.lock().unwrap();
+        let variant = match *&mut *lock {
+            CodeVariant::Empty => CodeVariant::Empty,
+            CodeVariant::UString { ref s, .. } => CodeVariant::UString { s: s.clone(), peek_index: 0 },
             ref mut code_variant @ CodeVariant::UStringIter(_) => {
                 if let CodeVariant::UStringIter(it) = mem::replace(code_variant, CodeVariant::Mutation) {
                     let s = it.collect::<UString>();
                     *code_variant = CodeVariant::UString { s: s.clone(), peek_index: 0 };
-                    Code(Mutex::new(CodeVariant::UString { s: s, peek_index: 0 }))
+                    CodeVariant::UString { s: s, peek_index: 0 }
                 } else {
                     unreachable!()
                 }
             }
             CodeVariant::Mutation => panic!("code mutex has been emptied")
+        };
+        Code {
+            variant: Mutex::new(variant),
+            source: self.source.clone(),
+            position: self.position
         }
     }
 }
@@ -112,43 +271,205 @@ impl Iterator for Code {
     type Item = char;
 
     fn next(&mut self) -> Option<char> {
-        let mut lock = self.0.lock().unwrap();
-        match *&mut *lock {
-            CodeVariant::Empty => None,
-            ref mut code_variant @ CodeVariant::UString { .. } => {
-                if let CodeVariant::UString { s, .. } = mem::replace(code_variant, CodeVariant::Mutation) {
-                    let mut iter = s.into_iter().multipeek();
-                    let result = iter.next();
-                    *code_variant = CodeVariant::UStringIter(iter);
-                    result
-                } else {
-                    unreachable!()
+        let next = {
+            let mut lock = self.variant.lock().unwrap();
+            match *&mut *lock {
+                CodeVariant::Empty => None,
+                ref mut code_variant @ CodeVariant::UString { .. } => {
+                    if let CodeVariant::UString { s, .. } = mem::replace(code_variant, CodeVariant::Mutation) {
+                        let mut iter = s.into_iter().multipeek();
+                        let result = iter.next();
+                        *code_variant = CodeVariant::UStringIter(iter);
+                        result
+                    } else {
+                        unreachable!()
+                    }
                 }
+                CodeVariant::UStringIter(ref mut iter) => iter.next(),
+                CodeVariant::Mutation => panic!("code mutex has been emptied")
+            }
+        };
+        if let Some(c) = next {
+            self.position.offset += 1;
+            self.position.byte_offset += c.len_utf8();
+            if c == '\n' {
+                self.position.line += 1;
+                self.position.column = 0;
+            } else {
+                self.position.column += 1;
             }
-            CodeVariant::UStringIter(ref mut iter) => iter.next(),
-            CodeVariant::Mutation => panic!("code mutex has been emptied")
         }
+        next
     }
 }
 
 struct Tokens {
     code: Code,
-    // context: Context
+    context: Context
 }
 
 impl Tokens {
-    fn new<T: Into<Code>>(code: T, _: Context) -> Tokens {
+    fn new<T: Into<Code>>(code: T, context: Context) -> Tokens {
         Tokens {
             code: code.into(),
-            // context: context
+            context: context
         }
     }
-}
 
-impl Iterator for Tokens {
-    type Item = Token;
+    /// Reads the longest operator symbol registered in the context which starts with `first`.
+    fn operator(&mut self, first: char) -> Token {
+        let symbols = self.context.operators.values().flat_map(|group| match *group {
+            PrecedenceGroup::Alternative => vec!["//".to_owned()],
+            PrecedenceGroup::Assign(ref ops) => iter::once("=".to_owned()).chain(ops.iter().map(|op| format!("{}=", op.label()))).collect(),
+            PrecedenceGroup::Infix(ref ops) => ops.iter().map(|op| op.label().to_owned()).collect(),
+            _ => vec![]
+        }).collect::<Vec<_>>();
+        let mut symbol = first.to_string();
+        loop {
+            match self.code.peek() {
+                Some(c) if symbols.iter().any(|s| s.starts_with(&format!("{}{}", symbol, c))) => {
+                    self.code.next();
+                    symbol.push(c);
+                }
+                _ => { break; }
+            }
+        }
+        if symbols.contains(&symbol) {
+            Token::Operator(symbol)
+        } else {
+            Token::Invalid(first)
+        }
+    }
 
-    fn next(&mut self) -> Option<Token> {
+    /// Reads an identifier whose first character has already been consumed. Its parts may be separated by `::`, like in `foo::bar`.
+    fn identifier(&mut self, first: char) -> UString {
+        let mut name = vec![first];
+        loop {
+            match self.code.peek() {
+                Some(c) if c.is_alphanumeric() || c == '_' => {
+                    self.code.next();
+                    name.push(c);
+                }
+                Some(':') => {
+                    // a `::` is only part of the identifier if another part follows it
+                    match (self.code.peek(), self.code.peek()) {
+                        (Some(':'), Some(c)) if c.is_alphabetic() || c == '_' => {
+                            self.code.next();
+                            self.code.next();
+                            self.code.next();
+                            name.extend(vec![':', ':', c]);
+                        }
+                        _ => { break; }
+                    }
+                }
+                _ => { break; }
+            }
+        }
+        name.into_iter().collect()
+    }
+
+    /// Reads the four hex digits of a `\\u` escape.
+    fn hex_escape(&mut self) -> Result<u32, Token> {
+        let mut code_point = 0;
+        for _ in 0..4 {
+            match self.code.next() {
+                Some(c) => {
+                    if let Some(digit) = c.to_digit(16) {
+                        code_point = code_point * 16 + digit;
+                    } else {
+                        return Err(Token::Invalid(c));
+                    }
+                }
+                None => { return Err(Token::Invalid('u')); }
+            }
+        }
+        Ok(code_point)
+    }
+
+    /// Reads a string literal whose opening quote has already been consumed.
+    ///
+    /// If the string contains interpolations, the code inside them is tokenized recursively.
+    fn string(&mut self) -> Token {
+        let mut s = String::new();
+        let mut parts = vec![];
+        let mut interpolations = vec![];
+        loop {
+            match self.code.next() {
+                Some('"') => {
+                    if interpolations.is_empty() {
+                        return Token::Literal(Value::String(UString::from(s)));
+                    } else {
+                        parts.push(UString::from(s));
+                        return Token::InterpolatedString(parts, interpolations);
+                    }
+                }
+                Some('\\') => {
+                    match self.code.next() {
+                        Some('(') => {
+                            parts.push(UString::from(mem::replace(&mut s, String::new())));
+                            match self.interpolation() {
+                                Ok(tokens) => { interpolations.push(tokens); }
+                                Err(token) => { return token; }
+                            }
+                        }
+                        Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => { s.push(c); }
+                        Some('b') => { s.push('\u{8}'); }
+                        Some('f') => { s.push('\u{c}'); }
+                        Some('n') => { s.push('\n'); }
+                        Some('r') => { s.push('\r'); }
+                        Some('t') => { s.push('\t'); }
+                        Some('u') => {
+                            let mut code_point = match self.hex_escape() { Ok(cp) => cp, Err(token) => { return token; } };
+                            if code_point >= 0xd800 && code_point < 0xdc00 {
+                                // a high surrogate, which must be followed by an escaped low surrogate
+                                if self.code.next() != Some('\\') || self.code.next() != Some('u') {
+                                    return Token::Invalid('u');
+                                }
+                                let low = match self.hex_escape() { Ok(cp) => cp, Err(token) => { return token; } };
+                                if low < 0xdc00 || low >= 0xe000 {
+                                    return Token::Invalid('u');
+                                }
+                                code_point = 0x10000 + ((code_point - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            match char::from_u32(code_point) {
+                                Some(c) => { s.push(c); }
+                                None => { return Token::Invalid('u'); }
+                            }
+                        }
+                        Some(c) => { return Token::Invalid(c); }
+                        None => { return Token::Invalid('\\'); }
+                    }
+                }
+                Some(c) => { s.push(c); }
+                None => { return Token::Invalid('"'); } // unterminated string literal
+            }
+        }
+    }
+
+    /// Reads the tokens of an interpolated filter whose opening `\\(` has already been consumed, up to the matching closing paren.
+    fn interpolation(&mut self) -> Result<Vec<(Token, Span)>, Token> {
+        let mut tokens = vec![];
+        let mut depth = 0;
+        loop {
+            match self.next() {
+                Some((Token::OpenParen, span)) => {
+                    depth += 1;
+                    tokens.push((Token::OpenParen, span));
+                }
+                Some((Token::CloseParen, span)) => {
+                    if depth == 0 { return Ok(tokens); }
+                    depth -= 1;
+                    tokens.push((Token::CloseParen, span));
+                }
+                Some((Token::AndThen(_), _)) => { return Err(Token::Invalid(';')); } // `;;` can't be used inside a string
+                Some(token) => { tokens.push(token); }
+                None => { return Err(Token::Invalid('"')); } // unterminated string literal
+            }
+        }
+    }
+
+    /// Reads the next token.
+    fn token(&mut self) -> Option<Token> {
         use self::Token::*;
 
         match self.code.next() {
@@ -162,83 +483,965 @@ impl Iterator for Tokens {
                 }
                 Some(Whitespace) // comments are treated as whitespace
             }
+            Some('"') => Some(self.string()),
             Some('(') => Some(OpenParen),
             Some(')') => Some(CloseParen),
+            Some('.') => {
+                match self.code.peek() {
+                    Some(c) if c.is_alphabetic() || c == '_' => {
+                        self.code.next();
+                        Some(Field(self.identifier(c)))
+                    }
+                    Some('"') => {
+                        self.code.next();
+                        match self.string() {
+                            Literal(Value::String(name)) => Some(Field(name)),
+                            InterpolatedString(_, _) => Some(Invalid('(')), //TODO support interpolated field names
+                            token => Some(token)
+                        }
+                    }
+                    Some('.') => {
+                        self.code.next();
+                        Some(DotDot)
+                    }
+                    _ => Some(Dot)
+                }
+            }
+            Some(':') => Some(Colon),
+            Some('@') => {
+                match self.code.peek() {
+                    Some(c) if c.is_alphabetic() => {
+                        self.code.next();
+                        Some(Format(self.identifier(c)))
+                    }
+                    _ => Some(Invalid('@'))
+                }
+            }
+            Some('?') => {
+                if self.code.peek() == Some('/') && self.code.peek() == Some('/') {
+                    self.code.next();
+                    self.code.next();
+                    Some(DestructuringAlternative)
+                } else {
+                    Some(Question)
+                }
+            }
+            Some('|') => {
+                if self.code.peek() == Some('=') {
+                    self.code.next();
+                    Some(Operator("|=".to_owned()))
+                } else {
+                    Some(Pipe)
+                }
+            }
+            Some(',') => Some(Comma),
             Some(';') => {
                 if self.code.peek() == Some(';') {
                     self.code.next(); // discard the second semicolon
-                    Some(AndThen(mem::replace(&mut self.code, Code::default())))
+                    let position = self.code.position;
+                    let remaining_code = mem::replace(&mut self.code, Code::default());
+                    self.code.position = position; // for the span of the `;;`
+                    Some(AndThen(remaining_code))
                 } else {
-                    Some(Invalid(';'))
+                    Some(Semicolon)
+                }
+            }
+            Some('$') => {
+                match self.code.peek() {
+                    Some(c) if c.is_alphabetic() || c == '_' => {
+                        self.code.next();
+                        Some(Variable(self.identifier(c)))
+                    }
+                    _ => Some(Invalid('$'))
                 }
             }
-            Some(c) => Some(Invalid(c)),
+            Some('[') => Some(OpenBracket),
+            Some(']') => Some(CloseBracket),
+            Some('{') => Some(OpenBrace),
+            Some('}') => Some(CloseBrace),
+            Some(c) if c.is_digit(10) => Some(number(&mut self.code, c.to_string())),
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let name = self.identifier(c);
+                match &String::from(&name)[..] {
+                    "false" => Some(Literal(Value::Boolean(false))),
+                    "null" => Some(Literal(Value::Null)),
+                    "true" => Some(Literal(Value::Boolean(true))),
+                    _ => Some(Ident(name))
+                }
+            }
+            Some(c) => Some(self.operator(c)),
             None => None
         }
     }
 }
 
-/// A token or filter, used by the in-place parsing algorithm.
+impl Iterator for Tokens {
+    type Item = (Token, Span);
+
+    fn next(&mut self) -> Option<(Token, Span)> {
+        let start = self.code.position;
+        self.token().map(|token| (token, Span { start: start, end: self.code.position }))
+    }
+}
+
+/// The largest number of decimal places by which the digits of a number literal can be shifted, which is somewhat more than the range of a double.
+const MAX_DECIMAL_SHIFT: i64 = 1000;
+
+/// Reads a number literal whose first digit has already been consumed from `code`.
+///
+/// The number is converted exactly, so `0.1` is really one tenth.
+fn number(code: &mut Code, mut integer: String) -> Token {
+    let mut fraction: Option<String> = None; // the digits after the decimal point, once one has been read
+    let mut exponent: Option<String> = None; // the sign and digits of the exponent, once an `e` has been read
+    loop {
+        match code.peek() {
+            Some(c) if c.is_digit(10) => {
+                code.next();
+                if let Some(ref mut exponent) = exponent {
+                    exponent.push(c);
+                } else if let Some(ref mut fraction) = fraction {
+                    fraction.push(c);
+                } else {
+                    integer.push(c);
+                }
+            }
+            Some('.') if fraction.is_none() && exponent.is_none() => {
+                code.next();
+                fraction = Some(String::new());
+            }
+            Some('e') | Some('E') if exponent.is_none() => {
+                code.next();
+                exponent = Some(String::new());
+            }
+            Some(c @ '+') | Some(c @ '-') if exponent == Some(String::new()) => {
+                code.next();
+                exponent = Some(c.to_string());
+            }
+            _ => { break; }
+        }
+    }
+    let fraction = fraction.unwrap_or(String::new());
+    let exponent = match exponent.map_or(Ok(0), |exponent| exponent.parse::<i32>()) {
+        Ok(exponent) => exponent,
+        Err(_) => { return Token::Invalid('e'); }
+    };
+    let mantissa = match (integer.clone() + &fraction).parse::<BigInt>() {
+        Ok(mantissa) => mantissa,
+        Err(_) => { return Token::Invalid(integer.chars().next().unwrap_or('.')); }
+    };
+    let shift = exponent as i64 - fraction.len() as i64;
+    if shift.abs() > MAX_DECIMAL_SHIFT { return Token::Invalid('e'); } // computing the power of ten would take too long
+    let ten = BigInt::from_u32(10).unwrap();
+    Token::Literal(Value::Number(if shift >= 0 {
+        BigRational::from_integer(mantissa * num::pow(ten, shift as usize))
+    } else {
+        BigRational::new(mantissa, num::pow(ten, -shift as usize))
+    }))
+}
+
+/// Parses a number like `-1.5e3` exactly, the same way number literals are parsed. Returns `None` if `s` is not a number.
+pub fn parse_number(s: &str) -> Option<BigRational> {
+    let (negative, digits) = if s.starts_with('-') { (true, &s[1..]) } else { (false, s) };
+    let mut code = Code::from(digits);
+    let first = match code.next() {
+        Some(c) if c.is_digit(10) => c,
+        _ => { return None; }
+    };
+    match (number(&mut code, first.to_string()), code.next()) {
+        (Token::Literal(Value::Number(n)), None) => Some(if negative { -n } else { n }),
+        _ => None
+    }
+}
+
+/// A token or filter, used by the in-place parsing algorithm, along with the span of code it was parsed from.
 #[derive(Debug)]
 pub enum Tf {
-    Token(Token),
-    Filter(Filter)
+    Token(Token, Span),
+    Filter(Filter, Span)
+}
+
+impl Tf {
+    /// The span of code this token or filter was parsed from.
+    pub fn span(&self) -> Span {
+        match *self {
+            Tf::Token(_, span) | Tf::Filter(_, span) => span
+        }
+    }
+}
+
+/// Returns the span from the first to the last of the tokens and filters, or `default` if there are none.
+fn span_of(tf: &[Tf], default: Span) -> Span {
+    match (tf.first(), tf.last()) {
+        (Some(first), Some(last)) => first.span().to(last.span()),
+        (_, _) => default
+    }
+}
+
+/// Returns how much the token changes the nesting depth of parens, brackets, and braces.
+fn depth_change(tf_item: &Tf) -> i32 {
+    match *tf_item {
+        Tf::Token(Token::OpenParen, _) | Tf::Token(Token::OpenBracket, _) | Tf::Token(Token::OpenBrace, _) => 1,
+        Tf::Token(Token::CloseParen, _) | Tf::Token(Token::CloseBracket, _) | Tf::Token(Token::CloseBrace, _) => -1,
+        Tf::Token(ref token, _) if is_keyword(token, "if") => 1,
+        Tf::Token(ref token, _) if is_keyword(token, "end") => -1,
+        _ => 0
+    }
+}
+
+/// Removes the whitespace directly after `idx`.
+fn skip_whitespace(tf: &mut Vec<Tf>, idx: usize) {
+    while idx + 1 < tf.len() {
+        if let Tf::Token(Token::Whitespace, _) = tf[idx + 1] {
+            tf.remove(idx + 1);
+        } else {
+            break;
+        }
+    }
+}
+
+/// Removes the whitespace after `idx`, then removes and returns the filter following it and its span, if any.
+fn take_operand(tf: &mut Vec<Tf>, idx: usize) -> Option<(Filter, Span)> {
+    skip_whitespace(tf, idx);
+    if let Some(&Tf::Filter(_, _)) = tf.get(idx + 1) {
+        if let Tf::Filter(f, span) = tf.remove(idx + 1) { Some((f, span)) } else { unreachable!() }
+    } else {
+        None
+    }
+}
+
+/// Words which have a special meaning in some position, and therefore can't be used as function names.
+const KEYWORDS: &'static [&'static str] = &["and", "as", "break", "catch", "def", "elif", "else", "end", "foreach", "if", "label", "or", "raise", "reduce", "then", "try"];
+
+/// Returns true if the token is an identifier which is not a keyword.
+fn is_function_name(token: &Token) -> bool {
+    if let Token::Ident(_) = *token {
+        !KEYWORDS.iter().any(|&keyword| is_keyword(token, keyword))
+    } else {
+        false
+    }
+}
+
+/// Returns true if the token is the identifier `keyword`.
+fn is_keyword(token: &Token, keyword: &str) -> bool {
+    if let Token::Ident(ref name) = *token { String::from(name) == keyword } else { false }
+}
+
+/// Splits unparsed tokens at each token for which `is_separator` returns true, ignoring nested parens, brackets, and braces.
+fn split_top_level<F: FnMut(&Token) -> bool>(tf: Vec<Tf>, mut is_separator: F) -> Vec<Vec<Tf>> {
+    let mut result = vec![vec![]];
+    let mut depth = 0;
+    for tf_item in tf {
+        depth += depth_change(&tf_item);
+        if depth == 0 {
+            if let Tf::Token(ref token, _) = tf_item {
+                if is_separator(token) {
+                    result.push(vec![]);
+                    continue;
+                }
+            }
+        }
+        result.last_mut().unwrap().push(tf_item);
+    }
+    result
+}
+
+/// Splits the unparsed contents of parens at the semicolons separating arguments, like those of a function call, `reduce`, or `foreach`. A semicolon ending a function definition like `def f: 1;` does not separate arguments.
+fn split_arguments(tf: Vec<Tf>) -> Vec<Vec<Tf>> {
+    let mut open_definitions = 0;
+    split_top_level(tf, |token| match *token {
+        Token::Semicolon if open_definitions > 0 => {
+            open_definitions -= 1;
+            false
+        }
+        Token::Semicolon => true,
+        ref token => {
+            if is_keyword(token, "def") { open_definitions += 1; }
+            false
+        }
+    })
+}
+
+/// Removes leading and trailing whitespace from a list of tokens and filters.
+fn trim_whitespace(tf: &mut Vec<Tf>) {
+    while let Some(&Tf::Token(Token::Whitespace, _)) = tf.first() { tf.remove(0); }
+    while let Some(&Tf::Token(Token::Whitespace, _)) = tf.last() { tf.pop(); }
+}
+
+/// Merges each operator token for which `is_op` returns true with the filters on its left and right, using `merge`, which also gets the span of the merged filter.
+///
+/// Whitespace is removed, since it is not significant for infix operators.
+fn parse_infix<F, G>(tf: &mut Vec<Tf>, right_assoc: bool, is_op: F, mut merge: G) -> Result<(), ParseError>
+where F: Fn(&Token) -> bool, G: FnMut(Token, Filter, Filter, Span) -> Result<Filter, ParseError> {
+    tf.retain(|tf_item| if let Tf::Token(Token::Whitespace, _) = *tf_item { false } else { true });
+    let mut idx = if right_assoc { tf.len() } else { 0 };
+    loop {
+        if right_assoc {
+            if idx == 0 { break; }
+            idx -= 1;
+        } else if idx >= tf.len() {
+            break;
+        }
+        if let Tf::Token(ref token, _) = tf[idx] {
+            if !is_op(token) {
+                if !right_assoc { idx += 1; }
+                continue;
+            }
+        } else {
+            if !right_assoc { idx += 1; }
+            continue;
+        }
+        let has_operands = idx > 0 && idx + 1 < tf.len() && match (&tf[idx - 1], &tf[idx + 1]) {
+            (&Tf::Filter(_, _), &Tf::Filter(_, _)) => true,
+            (_, _) => false
+        };
+        if !has_operands {
+            if let Tf::Token(token, span) = tf.remove(idx) {
+                return Err(ParseErrorKind::MissingOperand(token).at(span));
+            } else {
+                unreachable!();
+            }
+        }
+        let (rhs, rhs_span) = if let Tf::Filter(rhs, span) = tf.remove(idx + 1) { (rhs, span) } else { unreachable!() };
+        let op = if let Tf::Token(op, _) = tf.remove(idx) { op } else { unreachable!() };
+        idx -= 1;
+        let (lhs, lhs_span) = if let Tf::Filter(lhs, span) = tf.remove(idx) { (lhs, span) } else { unreachable!() };
+        let span = lhs_span.to(rhs_span);
+        tf.insert(idx, Tf::Filter(try!(merge(op, lhs, rhs, span)), span));
+        if !right_assoc { idx += 1; }
+    }
+    Ok(())
+}
+
+/// Returns a call to the function with the given name and arguments, or an `UnknownFunction` error if the context does not define it.
+///
+/// A function from an enclosing definition, or a parameter of one, is called by name and bound when that definition runs, see `parse_definition`. Other functions are taken from the context now, so defining a function with the same name later does not change the call.
+fn call(name: UString, args: Vec<Filter>, span: Span, context: &Context) -> Result<Filter, ParseError> {
+    let key = (name, args.len());
+    let f = if context.local_functions.contains(&key) {
+        Filter::Call {
+            name: key.0,
+            args: args
+        }
+    } else if let Some(function) = context.functions.get(&key) {
+        Filter::Apply {
+            function: function.clone(),
+            args: args
+        }
+    } else {
+        return Err(ParseErrorKind::UnknownFunction(key.0, key.1).at(span));
+    };
+    check_allowed(f, span, context)
+}
+
+/// Returns the filter if the context allows it, or a `NotAllowed` error at `span` otherwise.
+fn check_allowed(f: Filter, span: Span, context: &Context) -> Result<Filter, ParseError> {
+    if (context.filter_allowed)(&f) {
+        Ok(f)
+    } else {
+        Err(ParseErrorKind::NotAllowed(f).at(span))
+    }
+}
+
+/// Converts the left-hand side of a variable binding, which has been parsed as a filter, into a pattern.
+///
+/// Array patterns are parsed like array constructions, and object patterns like object constructions, so `{$a, b: [$c]}` is also a valid pattern.
+fn to_pattern(f: Filter, span: Span) -> Result<Pattern, ParseError> {
+    match f {
+        Filter::Variable(name) => Ok(Pattern::Variable(name)),
+        Filter::Array(inner) => {
+            let mut items = vec![];
+            let mut remaining = vec![*inner];
+            while let Some(item) = remaining.pop() {
+                match item {
+                    Filter::Comma { lhs, rhs } => {
+                        remaining.push(*rhs);
+                        remaining.push(*lhs);
+                    }
+                    Filter::Empty => {}
+                    item => { items.push(try!(to_pattern(item, span))); }
+                }
+            }
+            Ok(Pattern::Array(items))
+        }
+        Filter::Object(entries) => {
+            let mut pattern_entries = vec![];
+            for (key, value) in entries {
+                pattern_entries.push((key, try!(to_pattern(value, span))));
+            }
+            Ok(Pattern::Object(pattern_entries))
+        }
+        f => Err(ParseErrorKind::InvalidPattern(f).at(span))
+    }
+}
+
+/// If the items before `tf[end]` are the `as` keyword and patterns of a variable binding like `f as $x` or `f as [$x] ?// $x`, removes them from `tf`.
+///
+/// Returns the index of the filter before the `as` keyword, which is the source of the binding, along with the patterns and their span.
+fn take_patterns(tf: &mut Vec<Tf>, end: usize) -> Result<Option<(usize, Vec<Pattern>, Span)>, ParseError> {
+    // find the `as` keyword before the patterns, if any
+    let mut binding_start = None;
+    let mut pattern_start = end;
+    while pattern_start >= 2 && if let Tf::Filter(_, _) = tf[pattern_start - 1] { true } else { false } {
+        match tf[pattern_start - 2] {
+            Tf::Token(ref token, _) if is_keyword(token, "as") => {
+                binding_start = Some(pattern_start - 2);
+                break;
+            }
+            Tf::Token(Token::DestructuringAlternative, _) => { pattern_start -= 2; }
+            _ => { break; }
+        }
+    }
+    let binding_start = if let Some(binding_start) = binding_start { binding_start } else { return Ok(None); };
+    if binding_start == 0 || if let Tf::Filter(_, _) = tf[binding_start - 1] { false } else { true } {
+        return Err(ParseErrorKind::MissingOperand(Token::Ident(UString::from("as"))).at(tf[binding_start].span()));
+    }
+    let patterns_span = tf[binding_start + 1].span().to(tf[end - 1].span());
+    let mut patterns = vec![];
+    for _ in binding_start..end {
+        if let Tf::Filter(pattern, pattern_span) = tf.remove(binding_start) {
+            patterns.push(try!(to_pattern(pattern, pattern_span)));
+        }
+    }
+    Ok(Some((binding_start - 1, patterns, patterns_span)))
+}
+
+/// Parses the tokens of a string literal with interpolations, whose span is `span`.
+fn parse_interpolation(parts: Vec<UString>, interpolations: Vec<Vec<(Token, Span)>>, format: Option<FormatFn>, span: Span, context: &Context) -> Result<Filter, ParseError> {
+    let mut filters = vec![];
+    for tokens in interpolations {
+        filters.push(try!(parse_inner(tokens.into_iter().map(|(token, token_span)| Tf::Token(token, token_span)), span, context.clone())));
+    }
+    check_allowed(Filter::Interpolation {
+        parts: parts,
+        interpolations: filters,
+        format: format
+    }, span, context)
+}
+
+/// Parses the contents of an object construction `{...}` into pairs of key and value filters.
+fn parse_object_entries(inner: Vec<Tf>, span: Span, context: Context) -> Result<Vec<(Filter, Filter)>, ParseError> {
+    let mut entries = vec![];
+    for mut entry in split_top_level(inner, |token| if let Token::Comma = *token { true } else { false }) {
+        trim_whitespace(&mut entry);
+        if entry.len() == 0 { continue; } // allow trailing commas
+        let entry_span = span_of(&entry, span);
+        let mut parts = split_top_level(entry, |token| if let Token::Colon = *token { true } else { false });
+        for part in &mut parts {
+            trim_whitespace(part);
+        }
+        let key_shorthand = |part: &[Tf]| if part.len() == 1 {
+            match part[0] {
+                Tf::Token(Token::Ident(ref name), _) => Some(name.clone()),
+                Tf::Token(Token::Literal(Value::String(ref name)), _) => Some(name.clone()),
+                // the keywords `null`, `true`, and `false` are names too
+                Tf::Token(Token::Literal(ref keyword @ Value::Null), _) |
+                Tf::Token(Token::Literal(ref keyword @ Value::Boolean(_)), _) => Some(UString::from(keyword.to_string())),
+                _ => None
+            }
+        } else {
+            None
+        };
+        if parts.len() == 2 {
+            // `key: value`, where key is a filter but may also be given as an identifier
+            let value = try!(parse_inner(parts.pop().unwrap(), entry_span, context.clone()));
+            let key = parts.pop().unwrap();
+            let key = if let Some(name) = key_shorthand(&key) {
+                try!(check_allowed(Filter::Constant(Value::String(name)), entry_span, &context))
+            } else {
+                try!(parse_inner(key, entry_span, context.clone()))
+            };
+            entries.push((key, value));
+        } else if parts.len() == 1 {
+            // `foo` or `"foo"`, which is short for `foo: .foo`, or `$foo`, which is short for `foo: $foo`
+            let key = parts.pop().unwrap();
+            let variable = if key.len() == 1 {
+                if let Tf::Token(Token::Variable(ref name), _) = key[0] { Some(name.clone()) } else { None }
+            } else {
+                None
+            };
+            if let Some(name) = variable {
+                entries.push((try!(check_allowed(Filter::Constant(Value::String(name.clone())), entry_span, &context)), try!(check_allowed(Filter::Variable(name), entry_span, &context))));
+            } else if let Some(name) = key_shorthand(&key) {
+                let value = Filter::Index {
+                    lhs: Box::new(try!(check_allowed(Filter::Identity, entry_span, &context))),
+                    index: Box::new(try!(check_allowed(Filter::Constant(Value::String(name.clone())), entry_span, &context)))
+                };
+                entries.push((try!(check_allowed(Filter::Constant(Value::String(name)), entry_span, &context)), try!(check_allowed(value, entry_span, &context))));
+            } else {
+                return Err(ParseErrorKind::NotFullyParsed(key).at(entry_span));
+            }
+        } else {
+            return Err(ParseErrorKind::NotFullyParsed(parts.into_iter().flat_map(|part| part).collect()).at(entry_span));
+        }
+    }
+    Ok(entries)
+}
+
+/// Parses the tokens between `if` and `end` into a conditional filter. `elif` branches become nested conditionals, and a missing `else` branch defaults to the identity filter.
+///
+/// `span` is the span of the whole conditional, including `if` and `end`.
+fn parse_conditional(inner: Vec<Tf>, span: Span, context: Context) -> Result<Filter, ParseError> {
+    // split the tokens at the top-level keywords, remembering the keyword preceding each section and its span
+    let mut sections = vec![("if", span, vec![])];
+    let mut depth = 0;
+    for tf_item in inner {
+        depth += depth_change(&tf_item);
+        let keyword = match tf_item {
+            Tf::Token(ref token, _) if depth == 0 => ["then", "elif", "else"].iter().cloned().find(|&keyword| is_keyword(token, keyword)),
+            _ => None
+        };
+        if let Some(keyword) = keyword {
+            sections.push((keyword, tf_item.span(), vec![]));
+        } else {
+            sections.last_mut().unwrap().2.push(tf_item);
+        }
+    }
+    // check the order of the keywords
+    let mut sections = sections.into_iter();
+    let mut branches = vec![];
+    let mut else_branch = None;
+    let mut condition = sections.next().unwrap();
+    loop {
+        match sections.next() {
+            Some(("then", then_span, body)) => { branches.push((condition, then_span, body)); }
+            Some((_, keyword_span, _)) => { return Err(ParseErrorKind::MissingKeyword("then").at(keyword_span)); }
+            None => { return Err(ParseErrorKind::MissingKeyword("then").at(span)); }
+        }
+        match sections.next() {
+            Some(("elif", elif_span, next_condition)) => { condition = ("elif", elif_span, next_condition); }
+            Some(("else", else_span, body)) => {
+                else_branch = Some((else_span, body));
+                if let Some((_, keyword_span, _)) = sections.next() { return Err(ParseErrorKind::MissingKeyword("end").at(keyword_span)); }
+                break;
+            }
+            Some((_, keyword_span, _)) => { return Err(ParseErrorKind::MissingKeyword("end").at(keyword_span)); }
+            None => { break; }
+        }
+    }
+    // build the conditional from the innermost branch outwards
+    let mut result = if let Some((else_span, else_branch)) = else_branch {
+        try!(parse_inner(else_branch, else_span, context.clone()))
+    } else {
+        try!(check_allowed(Filter::Identity, span, &context))
+    };
+    for ((keyword, keyword_span, mut condition), then_span, then_branch) in branches.into_iter().rev() {
+        trim_whitespace(&mut condition);
+        if condition.len() == 0 { return Err(ParseErrorKind::MissingOperand(Token::Ident(UString::from(keyword))).at(keyword_span)); }
+        result = try!(check_allowed(Filter::Conditional {
+            condition: Box::new(try!(parse_inner(condition, keyword_span, context.clone()))),
+            then_branch: Box::new(try!(parse_inner(then_branch, then_span, context.clone()))),
+            else_branch: Box::new(result)
+        }, span, &context));
+    }
+    Ok(result)
+}
+
+/// Parses the first function definition `def name(params): body;` which is not nested in parens, brackets, braces, or conditionals, along with all tokens after it.
+///
+/// The definition is replaced with a filter which makes the function available to the tokens after the definition. Any tokens before it are left in place. If `top_level` is true, the function is also exported to the output context.
+fn parse_definition(tf: &mut Vec<Tf>, context: &Context, top_level: bool) -> Result<(), ParseError> {
+    let mut depth = 0;
+    let start = match tf.iter().position(|tf_item| {
+        depth += depth_change(tf_item);
+        depth == 0 && if let Tf::Token(ref token, _) = *tf_item { is_keyword(token, "def") } else { false }
+    }) {
+        Some(start) => start,
+        None => { return Ok(()); }
+    };
+    let mut definition = tf.split_off(start);
+    let def_span = definition.remove(0).span(); // the `def` keyword
+    // the function name and parameters, up to the colon
+    let mut header = vec![];
+    loop {
+        if definition.is_empty() { return Err(ParseErrorKind::MissingKeyword(":").at(def_span.to(span_of(&header, def_span)))); }
+        match definition.remove(0) {
+            Tf::Token(Token::Colon, _) => { break; }
+            Tf::Token(Token::Whitespace, _) => {}
+            tf_item => { header.push(tf_item); }
+        }
+    }
+    let header_span = def_span.to(span_of(&header, def_span));
+    let name = match header.first() {
+        Some(&Tf::Token(Token::Ident(ref name), _)) if is_function_name(&Token::Ident(name.clone())) && !String::from(name).contains("::") => name.clone(),
+        _ => { return Err(ParseErrorKind::NotFullyParsed(header).at(header_span)); }
+    };
+    let mut params = vec![];
+    if header.len() > 1 {
+        // `(a; $b)`
+        let is_delimited = header.len() > 3 && match (&header[1], header.last().unwrap()) {
+            (&Tf::Token(Token::OpenParen, _), &Tf::Token(Token::CloseParen, _)) => true,
+            (_, _) => false
+        };
+        if !is_delimited || header.len() % 2 != 0 { return Err(ParseErrorKind::NotFullyParsed(header).at(header_span)); }
+        for (i, tf_item) in header[2..header.len() - 1].iter().enumerate() {
+            match (i % 2, tf_item) {
+                (0, &Tf::Token(Token::Variable(ref param), _)) => { params.push(Param::Value(param.clone())); }
+                (0, &Tf::Token(ref token, _)) if is_function_name(token) => {
+                    if let Token::Ident(ref param) = *token { params.push(Param::Filter(param.clone())); }
+                }
+                (1, &Tf::Token(Token::Semicolon, _)) => {}
+                (_, _) => { return Err(ParseErrorKind::NotFullyParsed(header).at(header_span)); }
+            }
+        }
+    }
+    // the body, up to the matching semicolon, which may also be the first of a `;;`
+    let mut body = vec![];
+    let mut depth = 0;
+    let mut nested_definitions = 0;
+    loop {
+        if definition.is_empty() { return Err(ParseErrorKind::MissingKeyword(";").at(def_span.to(span_of(&body, header_span)))); }
+        depth += depth_change(&definition[0]);
+        if depth == 0 {
+            match definition[0] {
+                Tf::Token(Token::AndThen(_), _) => { break; }
+                Tf::Token(Token::Semicolon, _) => {
+                    if nested_definitions == 0 {
+                        definition.remove(0);
+                        break;
+                    }
+                    nested_definitions -= 1;
+                }
+                Tf::Token(ref token, _) if is_keyword(token, "def") => { nested_definitions += 1; }
+                _ => {}
+            }
+        }
+        body.push(definition.remove(0));
+    }
+    // the body can call the function itself and its parameters, which are bound when the function is called
+    let mut body_context = context.clone();
+    for param in &params {
+        body_context.local_functions.insert((param.name().clone(), 0));
+    }
+    body_context.local_functions.insert((name.clone(), params.len()));
+    let body_span = span_of(&body, header_span);
+    let function = Function {
+        name: name,
+        params: params,
+        body: Arc::new(try!(parse_inner(body, body_span, body_context)))
+    };
+    let span = def_span.to(span_of(&definition, body_span));
+    // the function can use the variables and functions of its definition, so calls to it are bound when the definition runs
+    let mut rest_context = context.clone();
+    rest_context.local_functions.insert((function.name.clone(), function.arity()));
+    tf.push(Tf::Filter(try!(check_allowed(Filter::Def {
+        function: function,
+        rest: Box::new(try!(parse_filter(definition, span, rest_context, top_level))),
+        exported: top_level
+    }, span, context)), span));
+    Ok(())
+}
+
+/// Parses the postfix precedence group in place: terms, suffixes like `.foo` and `[0]`, prefix negation, and keywords taking their operands from the right.
+fn parse_postfix(tf: &mut Vec<Tf>, context: &Context) -> Result<(), ParseError> {
+    macro_rules! try_filter {
+        ($f:expr, $span:expr) => {
+            try!(check_allowed($f, $span, context))
+        }
+    }
+    let mut idx = 0;
+    while idx < tf.len() { // iterate left-to-right so that suffixes apply to the already parsed term on their left
+        let has_lhs = idx > 0 && if let Tf::Filter(_, _) = tf[idx - 1] { true } else { false };
+        let item_span = tf[idx].span();
+        macro_rules! lhs {
+            () => {
+                if has_lhs {
+                    idx -= 1;
+                    if let Tf::Filter(lhs, lhs_span) = tf.remove(idx) { (lhs, lhs_span.to(item_span)) } else { unreachable!() }
+                } else {
+                    (try_filter!(Filter::Identity, item_span), item_span)
+                }
+            }
+        }
+        match tf.remove(idx) {
+            Tf::Token(Token::Dot, _) => {
+                tf.insert(idx, Tf::Filter(try_filter!(Filter::Identity, item_span), item_span));
+            }
+            Tf::Token(Token::DotDot, _) => {
+                tf.insert(idx, Tf::Filter(try!(call(UString::from("recurse"), vec![], item_span, context)), item_span));
+            }
+            Tf::Token(Token::Literal(value), _) => {
+                tf.insert(idx, Tf::Filter(try_filter!(Filter::Constant(value), item_span), item_span));
+            }
+            Tf::Token(Token::InterpolatedString(parts, interpolations), _) => {
+                tf.insert(idx, Tf::Filter(try!(parse_interpolation(parts, interpolations, None, item_span, context)), item_span));
+            }
+            Tf::Token(Token::Format(name), _) => {
+                let format = try!(context.formats.get(&name).cloned().ok_or(ParseErrorKind::UnknownFormat(name).at(item_span)));
+                // a format followed by a string literal applies to its interpolations, otherwise it is a filter
+                let string_idx = tf[idx..].iter().position(|tf_item| if let Tf::Token(Token::Whitespace, _) = *tf_item { false } else { true }).map(|offset| idx + offset);
+                let (filter, filter_span) = match string_idx.map(|string_idx| &tf[string_idx]) {
+                    Some(&Tf::Token(Token::Literal(Value::String(_)), _)) | Some(&Tf::Token(Token::InterpolatedString(_, _), _)) => {
+                        for _ in idx..string_idx.unwrap() {
+                            tf.remove(idx); // whitespace between the format and the string
+                        }
+                        let string_span = tf[idx].span();
+                        let format_span = item_span.to(string_span);
+                        (match tf.remove(idx) {
+                            Tf::Token(Token::Literal(value), _) => try_filter!(Filter::Constant(value), format_span),
+                            Tf::Token(Token::InterpolatedString(parts, interpolations), _) => try!(parse_interpolation(parts, interpolations, Some(format), format_span, context)),
+                            _ => unreachable!()
+                        }, format_span)
+                    }
+                    _ => (try_filter!(Filter::Format(format), item_span), item_span)
+                };
+                tf.insert(idx, Tf::Filter(filter, filter_span));
+            }
+            Tf::Token(Token::Variable(name), _) => {
+                tf.insert(idx, Tf::Filter(try_filter!(Filter::Variable(name), item_span), item_span));
+            }
+            Tf::Token(Token::Ident(ref name), _) if is_function_name(&Token::Ident(name.clone())) => {
+                tf.insert(idx, Tf::Filter(try!(call(name.clone(), vec![], item_span, context)), item_span));
+            }
+            Tf::Token(Token::Field(name), _) => {
+                let (lhs, index_span) = lhs!();
+                tf.insert(idx, Tf::Filter(try_filter!(Filter::Index {
+                    lhs: Box::new(lhs),
+                    index: Box::new(try_filter!(Filter::Constant(Value::String(name)), item_span))
+                }, index_span), index_span));
+            }
+            Tf::Token(Token::Brackets(inner), _) => {
+                if !has_lhs {
+                    // not a suffix, so this is an array construction
+                    tf.insert(idx, Tf::Filter(try_filter!(Filter::Array(Box::new(try!(parse_inner(inner, item_span, context.clone())))), item_span), item_span));
+                    idx += 1;
+                    continue;
+                }
+                let (lhs, index_span) = lhs!();
+                if inner.iter().all(|tf_item| if let Tf::Token(Token::Whitespace, _) = *tf_item { true } else { false }) {
+                    // empty brackets iterate over the values of an array or object
+                    tf.insert(idx, Tf::Filter(try_filter!(Filter::Iterate(Box::new(lhs)), index_span), index_span));
+                    idx += 1;
+                    continue;
+                }
+                let mut parts = split_top_level(inner, |token| if let Token::Colon = *token { true } else { false });
+                tf.insert(idx, Tf::Filter(if parts.len() == 2 {
+                    // a slice, with either bound optional
+                    let to = parts.pop().unwrap();
+                    let from = parts.pop().unwrap();
+                    let bound = |bound: Vec<Tf>| -> Result<Option<Box<Filter>>, ParseError> {
+                        if bound.iter().all(|tf_item| if let Tf::Token(Token::Whitespace, _) = *tf_item { true } else { false }) {
+                            Ok(None)
+                        } else {
+                            Ok(Some(Box::new(try!(parse_inner(bound, item_span, context.clone())))))
+                        }
+                    };
+                    try_filter!(Filter::Slice {
+                        lhs: Box::new(lhs),
+                        from: try!(bound(from)),
+                        to: try!(bound(to))
+                    }, index_span)
+                } else if parts.len() == 1 {
+                    try_filter!(Filter::Index {
+                        lhs: Box::new(lhs),
+                        index: Box::new(try!(parse_inner(parts.pop().unwrap(), item_span, context.clone())))
+                    }, index_span)
+                } else {
+                    return Err(ParseErrorKind::NotFullyParsed(parts.into_iter().flat_map(|part| part).collect()).at(item_span));
+                }, index_span));
+            }
+            Tf::Token(Token::Question, _) => {
+                if !has_lhs {
+                    return Err(ParseErrorKind::MissingOperand(Token::Question).at(item_span));
+                }
+                let (body, try_span) = lhs!();
+                tf.insert(idx, Tf::Filter(try_filter!(Filter::Try {
+                    body: Box::new(body),
+                    handler: None
+                }, try_span), try_span));
+            }
+            tf_item => {
+                tf.insert(idx, tf_item);
+            }
+        }
+        idx += 1;
+    }
+    // a `-` with no left operand is a prefix operator which negates its right operand
+    for idx in (0..tf.len()).rev() {
+        if let Tf::Token(Token::Operator(ref symbol), _) = tf[idx] {
+            if symbol != "-" { continue; }
+        } else {
+            continue;
+        }
+        let has_lhs = tf[..idx].iter().rev().find(|tf_item| if let Tf::Token(Token::Whitespace, _) = **tf_item { false } else { true }).map_or(false, |tf_item| if let Tf::Filter(_, _) = *tf_item { true } else { false });
+        if has_lhs { continue; }
+        let minus_span = tf[idx].span();
+        match take_operand(tf, idx) {
+            Some((Filter::Constant(Value::Number(n)), rhs_span)) => {
+                tf[idx] = Tf::Filter(try_filter!(Filter::Constant(Value::Number(-n)), minus_span.to(rhs_span)), minus_span.to(rhs_span));
+            }
+            Some((rhs, rhs_span)) => {
+                tf[idx] = Tf::Filter(try_filter!(Filter::Negate(Box::new(rhs)), minus_span.to(rhs_span)), minus_span.to(rhs_span));
+            }
+            None => { return Err(ParseErrorKind::MissingOperand(Token::Operator("-".to_owned())).at(minus_span)); }
+        }
+    }
+    // `try`, `raise`, `reduce`, `foreach`, and `break` take their operands from the right
+    for idx in (0..tf.len()).rev() {
+        let keyword = match tf[idx] {
+            Tf::Token(ref token, _) if is_keyword(token, "break") => "break",
+            Tf::Token(ref token, _) if is_keyword(token, "try") => "try",
+            Tf::Token(ref token, _) if is_keyword(token, "raise") => "raise",
+            Tf::Token(ref token, _) if is_keyword(token, "reduce") => "reduce",
+            Tf::Token(ref token, _) if is_keyword(token, "foreach") => "foreach",
+            _ => { continue; }
+        };
+        let keyword_span = tf[idx].span();
+        let missing_operand = |keyword: &str| ParseErrorKind::MissingOperand(Token::Ident(UString::from(keyword))).at(keyword_span);
+        let (first, first_span) = try!(take_operand(tf, idx).ok_or(missing_operand(keyword)));
+        let (filter, filter_span) = match keyword {
+            "try" => {
+                // `try f` or `try f catch g`
+                skip_whitespace(tf, idx);
+                let has_handler = tf.get(idx + 1).map_or(false, |tf_item| if let Tf::Token(ref token, _) = *tf_item { is_keyword(token, "catch") } else { false });
+                let (handler, try_span) = if has_handler {
+                    tf.remove(idx + 1);
+                    let (handler, handler_span) = try!(take_operand(tf, idx).ok_or(missing_operand("catch")));
+                    (Some(Box::new(handler)), keyword_span.to(handler_span))
+                } else {
+                    (None, keyword_span.to(first_span))
+                };
+                (try_filter!(Filter::Try {
+                    body: Box::new(first),
+                    handler: handler
+                }, try_span), try_span)
+            }
+            "break" => {
+                if let Filter::Variable(name) = first {
+                    (try_filter!(Filter::Break(name), keyword_span.to(first_span)), keyword_span.to(first_span))
+                } else {
+                    return Err(missing_operand(keyword));
+                }
+            }
+            "raise" => {
+                // `raise name` or `raise name meta`
+                let (meta, raise_span) = if let Some((meta, meta_span)) = take_operand(tf, idx) {
+                    (meta, keyword_span.to(meta_span))
+                } else {
+                    (try_filter!(Filter::Constant(Value::Object(Object::default())), first_span), keyword_span.to(first_span))
+                };
+                (try_filter!(Filter::Raise {
+                    name: Box::new(first),
+                    meta: Box::new(meta)
+                }, raise_span), raise_span)
+            }
+            _ => {
+                // `reduce source as pattern (init; update)` or `foreach source as pattern (init; update; extract)`
+                skip_whitespace(tf, idx);
+                let has_as = tf.get(idx + 1).map_or(false, |tf_item| if let Tf::Token(ref token, _) = *tf_item { is_keyword(token, "as") } else { false });
+                if !has_as { return Err(ParseErrorKind::MissingKeyword("as").at(keyword_span.to(first_span))); }
+                tf.remove(idx + 1);
+                let (pattern, pattern_span) = try!(take_operand(tf, idx).ok_or(missing_operand("as")));
+                let pattern = try!(to_pattern(pattern, pattern_span));
+                skip_whitespace(tf, idx);
+                let (mut args, args_span) = match tf.get(idx + 1) {
+                    Some(&Tf::Token(Token::Arguments(_), _)) => if let Tf::Token(Token::Arguments(args), args_span) = tf.remove(idx + 1) { (args, args_span) } else { unreachable!() },
+                    _ => { return Err(missing_operand(keyword)); }
+                };
+                let loop_span = keyword_span.to(args_span);
+                if keyword == "reduce" && args.len() == 2 {
+                    let update = args.pop().unwrap();
+                    let init = args.pop().unwrap();
+                    (try_filter!(Filter::Reduce {
+                        source: Box::new(first),
+                        pattern: pattern,
+                        init: Box::new(init),
+                        update: Box::new(update)
+                    }, loop_span), loop_span)
+                } else if keyword == "foreach" && (args.len() == 2 || args.len() == 3) {
+                    let extract = if args.len() == 3 { Some(Box::new(args.pop().unwrap())) } else { None };
+                    let update = args.pop().unwrap();
+                    let init = args.pop().unwrap();
+                    (try_filter!(Filter::Foreach {
+                        source: Box::new(first),
+                        pattern: pattern,
+                        init: Box::new(init),
+                        update: Box::new(update),
+                        extract: extract
+                    }, loop_span), loop_span)
+                } else {
+                    return Err(ParseErrorKind::NotFullyParsed(vec![Tf::Token(Token::Arguments(args), args_span)]).at(args_span));
+                }
+            }
+        };
+        tf[idx] = Tf::Filter(filter, filter_span);
+    }
+    Ok(())
 }
 
 /// Convert a sequence of tokens into an executable filter.
 pub fn parse<T: Into<Code>>(code: T, context: Context) -> Result<Filter, ParseError> {
-    parse_inner(Tokens::new(code, context.clone()).map(Tf::Token), context)
+    let code = code.into();
+    let source = code.source.clone();
+    let span = Span { start: code.position, end: code.position };
+    parse_filter(Tokens::new(code, context.clone()).map(|(token, token_span)| Tf::Token(token, token_span)), span, context, true).map_err(|err| ParseError { source: source, ..err })
 }
 
-fn parse_inner<I: IntoIterator<Item = Tf>>(tf_iter: I, context: Context) -> Result<Filter, ParseError> {
+/// Parses tokens and filters nested in other code, like the body of a function or the contents of parens, into a single filter. `span` is the span of the surrounding code, which is used for errors if there are no tokens.
+fn parse_inner<I: IntoIterator<Item = Tf>>(tf_iter: I, span: Span, context: Context) -> Result<Filter, ParseError> {
+    parse_filter(tf_iter, span, context, false)
+}
+
+/// Parses tokens and filters into a single filter. Function definitions are exported if `top_level` is true, i.e. if the tokens are not nested in other code.
+fn parse_filter<I: IntoIterator<Item = Tf>>(tf_iter: I, span: Span, context: Context, top_level: bool) -> Result<Filter, ParseError> {
     let mut tf = tf_iter.into_iter().collect::<Vec<_>>(); // the list of tokens and filters on which the in-place parsing algorithm operates
     // error if any invalid token is found
-    if let Some(pos) = tf.iter().position(|i| if let Tf::Token(Token::Invalid(_)) = *i { true } else { false }) {
-        if let Tf::Token(Token::Invalid(c)) = tf[pos] {
-            return Err(ParseError::InvalidToken(c));
+    if let Some(pos) = tf.iter().position(|i| if let Tf::Token(Token::Invalid(_), _) = *i { true } else { false }) {
+        if let Tf::Token(Token::Invalid(c), invalid_span) = tf[pos] {
+            return Err(ParseErrorKind::InvalidToken(c).at(invalid_span));
         } else {
             unreachable!();
         }
     }
     // define the macro used for testing if filters are allowed
     macro_rules! try_filter {
-        ($f:expr) => {
-            match $f {
-                f => {
-                    if (context.filter_allowed)(&f) {
-                        f
-                    } else {
-                        return Err(ParseError::NotAllowed(f));
-                    }
-                }
-            }
+        ($f:expr, $span:expr) => {
+            try!(check_allowed($f, $span, &context))
         }
     }
     // remove leading and trailing whitespace as it is semantically irrelevant
-    while let Some(&Tf::Token(Token::Whitespace)) = tf.first() { tf.remove(0); }
-    while let Some(&Tf::Token(Token::Whitespace)) = tf.last() { tf.pop(); }
+    trim_whitespace(&mut tf);
     // return an empty filter if the token list is empty
-    if tf.len() == 0 { return Ok(try_filter!(Filter::Empty)); }
+    if tf.len() == 0 { return Ok(try_filter!(Filter::Empty, span)); }
+    // function definitions apply to everything after them, so they are parsed before any operators
+    try!(parse_definition(&mut tf, &context, top_level));
     // parse operators in decreasing precedence
     for (_, precedence_group) in context.operators.clone().into_iter().rev() { // iterate from highest to lowest precedence
         match precedence_group {
+            PrecedenceGroup::Alternative => {
+                try!(parse_infix(&mut tf, true, |token| if let Token::Operator(ref symbol) = *token { symbol == "//" } else { false }, |_, lhs, rhs, span| {
+                    Ok(try_filter!(Filter::Alternative {
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs)
+                    }, span))
+                }));
+            }
+            PrecedenceGroup::And => {
+                try!(parse_infix(&mut tf, false, |token| is_keyword(token, "and"), |_, lhs, rhs, span| {
+                    Ok(try_filter!(Filter::And {
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs)
+                    }, span))
+                }));
+            }
             PrecedenceGroup::AndThen => {
-                let mut found = None; // flag any AndThen tokens and remember their contents
+                let mut found = None; // flag any AndThen tokens and remember their contents and spans
                 for idx in (0..tf.len()).rev() { // iterate right-to-left for in-place manipulation
-                    if let Some(remaining_code) = mem::replace(&mut found, None) {
-                        if let Tf::Token(Token::Whitespace) = tf[idx] {
+                    if let Some((remaining_code, and_then_span)) = mem::replace(&mut found, None) {
+                        if let Tf::Token(Token::Whitespace, _) = tf[idx] {
                             // ignore whitespace between `;;` and its left operand
                             tf.remove(idx);
+                            found = Some((remaining_code, and_then_span));
                             continue;
                         }
+                        let lhs_span = tf[idx].span();
                         tf[idx] = Tf::Filter(try_filter!(Filter::AndThen {
-                            lhs: Box::new(if let Tf::Filter(ref lhs) = tf[idx] { lhs.clone() } else { try_filter!(Filter::Empty) }),
+                            lhs: Box::new(if let Tf::Filter(ref lhs, _) = tf[idx] { lhs.clone() } else { try_filter!(Filter::Empty, lhs_span) }),
                             remaining_code: remaining_code
-                        }));
+                        }, lhs_span.to(and_then_span)), lhs_span.to(and_then_span));
                     } else {
                         match tf.remove(idx) {
-                            Tf::Token(Token::AndThen(remaining_code)) => {
-                                found = Some(remaining_code); // found an AndThen (`;;`), will be merged into a syntax tree with the element to its left
+                            Tf::Token(Token::AndThen(remaining_code), and_then_span) => {
+                                found = Some((remaining_code, and_then_span)); // found an AndThen (`;;`), will be merged into a syntax tree with the element to its left
                             }
                             tf_item => {
                                 tf.insert(idx, tf_item);
@@ -246,52 +1449,117 @@ fn parse_inner<I: IntoIterator<Item = Tf>>(tf_iter: I, context: Context) -> Resu
                         }
                     }
                 }
-                if let Some(remaining_code) = found {
+                if let Some((remaining_code, and_then_span)) = found {
                     // the code begins with an `;;`
                     tf.insert(0, Tf::Filter(try_filter!(Filter::AndThen {
-                        lhs: Box::new(try_filter!(Filter::Empty)),
+                        lhs: Box::new(try_filter!(Filter::Empty, and_then_span)),
                         remaining_code: remaining_code
-                    })));
+                    }, and_then_span), and_then_span));
                 }
             }
+            PrecedenceGroup::Assign(ops) => {
+                let is_assign_op = |symbol: &str| symbol == "=" || symbol == "|=" || ops.iter().any(|op| format!("{}=", op.label()) == symbol);
+                try!(parse_infix(&mut tf, true, |token| if let Token::Operator(ref symbol) = *token { is_assign_op(symbol) } else { false }, |token, lhs, rhs, span| {
+                    let symbol = if let Token::Operator(symbol) = token { symbol } else { unreachable!() };
+                    Ok(try_filter!(match &symbol[..] {
+                        "=" => Filter::Assign {
+                            lhs: Box::new(lhs),
+                            op: None,
+                            rhs: Box::new(rhs)
+                        },
+                        "|=" => Filter::Update {
+                            lhs: Box::new(lhs),
+                            rhs: Box::new(rhs)
+                        },
+                        _ => Filter::Assign {
+                            lhs: Box::new(lhs),
+                            op: Some(ops.iter().find(|op| format!("{}=", op.label()) == symbol).unwrap().clone()),
+                            rhs: Box::new(rhs)
+                        }
+                    }, span))
+                }));
+            }
             PrecedenceGroup::Circumfix => {
-                let mut paren_balance = 0; // how many closing parens have not been matched by opening parens
-                let mut paren_start = None; // the index of the outermost closing paren
+                let mut paren_balance = 0; // how many closing parens, brackets, or braces have not been matched by opening ones
+                let mut paren_start = None; // the index of the outermost closing paren, bracket, or brace
                 for idx in (0..tf.len()).rev() { // iterate right-to-left for in-place manipulation
-                    match tf[idx] {
-                        Tf::Token(Token::CloseParen) => {
+                    match depth_change(&tf[idx]) {
+                        -1 => {
                             if paren_balance == 0 {
                                 paren_start = Some(idx);
                             }
                             paren_balance += 1;
                         }
-                        Tf::Token(Token::OpenParen) => {
+                        1 => {
                             paren_balance -= 1;
+                            let (open, open_span) = if let Tf::Token(open, open_span) = tf.remove(idx) { (open, open_span) } else { unreachable!() };
                             if paren_balance < 0 {
-                                return Err(ParseError::UnbalancedParen(Token::OpenParen));
+                                return Err(ParseErrorKind::UnbalancedParen(open).at(open_span));
                             } else if paren_balance == 0 {
                                 if let Some(paren_start) = paren_start {
-                                    if let Tf::Token(Token::CloseParen) = tf[paren_start] {
-                                        tf.remove(paren_start);
-                                        //let inner = tf.drain(idx + 1..paren_start).collect::<Vec<_>>(); //TODO use this when stabilized
-                                        let mut inner = vec![];
-                                        for _ in idx + 1..paren_start {
-                                            inner.push(tf.remove(idx + 1));
+                                    let close = tf.remove(paren_start - 1);
+                                    let group_span = open_span.to(close.span());
+                                    //let inner = tf.drain(idx..paren_start - 1).collect::<Vec<_>>(); //TODO use this when stabilized
+                                    let mut inner = vec![];
+                                    for _ in idx..paren_start - 1 {
+                                        inner.push(tf.remove(idx));
+                                    }
+                                    let is_call = idx > 0 && if let Tf::Token(ref token, _) = tf[idx - 1] { is_function_name(token) } else { false };
+                                    if let (true, &Token::OpenParen, &Tf::Token(Token::CloseParen, _)) = (is_call, &open, &close) {
+                                        // a function call with arguments like `f(a; b)`
+                                        let (name, name_span) = if let Tf::Token(Token::Ident(name), name_span) = tf.remove(idx - 1) { (name, name_span) } else { unreachable!() };
+                                        let mut args = vec![];
+                                        for arg in split_arguments(inner) {
+                                            args.push(try!(parse_inner(arg, group_span, context.clone())));
                                         }
-                                        tf[idx] = Tf::Filter(try_filter!(Filter::Custom {
-                                            attributes: vec![try!(parse_inner(inner, context.clone()))],
-                                            run: Box::new(Labeled::new("<filter group (α)>", Arc::new(|attrs, input, output| {
-                                                assert_eq!(attrs.len(), 1);
-                                                attrs[0].run(input, output)
-                                            })))
-                                        }));
+                                        let call_span = name_span.to(group_span);
+                                        tf.insert(idx - 1, Tf::Filter(try!(call(name, args, call_span, &context)), call_span));
                                     } else {
-                                        return Err(ParseError::MismatchedParens(Token::OpenParen, tf.remove(paren_start)));
+                                        tf.insert(idx, match (open, close) {
+                                            (Token::OpenParen, Tf::Token(Token::CloseParen, _)) => {
+                                                // semicolons separate the arguments of `reduce` or `foreach`, unless they end a function definition
+                                                let mut parts = split_arguments(inner);
+                                                if parts.len() > 1 {
+                                                    let mut args = vec![];
+                                                    for part in parts {
+                                                        args.push(try!(parse_inner(part, group_span, context.clone())));
+                                                    }
+                                                    Tf::Token(Token::Arguments(args), group_span)
+                                                } else {
+                                                    Tf::Filter(try_filter!(Filter::Custom {
+                                                        attributes: vec![try!(parse_inner(parts.pop().unwrap(), group_span, context.clone()))],
+                                                        run: Box::new(Labeled::new("<filter group (α)>", Arc::new(|attrs, input, output| {
+                                                            assert_eq!(attrs.len(), 1);
+                                                            attrs[0].run(input, output)
+                                                        }))),
+                                                        paths: Some(Box::new(Labeled::new("<filter group (α)>", Arc::new(|attrs, context, path, value, output| {
+                                                            assert_eq!(attrs.len(), 1);
+                                                            attrs[0].run_paths(context, path, value, output)
+                                                        }))))
+                                                    }, group_span), group_span)
+                                                }
+                                            }
+                                            (Token::OpenBracket, Tf::Token(Token::CloseBracket, _)) => {
+                                                // whether this is an index or something else is decided by the postfix group
+                                                Tf::Token(Token::Brackets(inner), group_span)
+                                            }
+                                            (Token::OpenBrace, Tf::Token(Token::CloseBrace, _)) => {
+                                                Tf::Filter(try_filter!(Filter::Object(try!(parse_object_entries(inner, group_span, context.clone()))), group_span), group_span)
+                                            }
+                                            (Token::Ident(ref open), Tf::Token(Token::Ident(ref close), _)) if String::from(open) == "if" && String::from(close) == "end" => {
+                                                Tf::Filter(try!(parse_conditional(inner, group_span, context.clone())), group_span)
+                                            }
+                                            (open, close) => {
+                                                return Err(ParseErrorKind::MismatchedParens(open, close).at(group_span));
+                                            }
+                                        });
                                     }
                                 } else {
                                     unreachable!();
                                 }
                                 paren_start = None;
+                            } else {
+                                tf.insert(idx, Tf::Token(open, open_span));
                             }
                         }
                         _ => { continue; }
@@ -299,8 +1567,8 @@ fn parse_inner<I: IntoIterator<Item = Tf>>(tf_iter: I, context: Context) -> Resu
                 }
                 if paren_balance > 0 {
                     if let Some(paren_start) = paren_start {
-                        if let Tf::Token(Token::CloseParen) = tf[paren_start] {
-                            return Err(ParseError::UnbalancedParen(Token::CloseParen));
+                        if let Tf::Token(token, span) = tf.remove(paren_start) {
+                            return Err(ParseErrorKind::UnbalancedParen(token).at(span));
                         } else {
                             unreachable!();
                         }
@@ -309,15 +1577,189 @@ fn parse_inner<I: IntoIterator<Item = Tf>>(tf_iter: I, context: Context) -> Resu
                     }
                 }
             }
+            PrecedenceGroup::Comma => {
+                try!(parse_infix(&mut tf, false, |token| if let Token::Comma = *token { true } else { false }, |_, lhs, rhs, span| {
+                    Ok(try_filter!(Filter::Comma {
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs)
+                    }, span))
+                }));
+            }
+            PrecedenceGroup::Infix(ops) => {
+                try!(parse_infix(&mut tf, false, |token| match *token {
+                    Token::Operator(ref symbol) => ops.iter().any(|op| op.label() == symbol),
+                    Token::Ident(ref name) => ops.iter().any(|op| op.label() == String::from(name)),
+                    _ => false
+                }, |token, lhs, rhs, span| {
+                    let symbol = match token {
+                        Token::Operator(symbol) => symbol,
+                        Token::Ident(name) => String::from(name),
+                        _ => unreachable!()
+                    };
+                    Ok(try_filter!(Filter::Infix {
+                        lhs: Box::new(lhs),
+                        op: ops.iter().find(|op| op.label() == symbol).unwrap().clone(),
+                        rhs: Box::new(rhs)
+                    }, span))
+                }));
+            }
+            PrecedenceGroup::Or => {
+                try!(parse_infix(&mut tf, false, |token| is_keyword(token, "or"), |_, lhs, rhs, span| {
+                    Ok(try_filter!(Filter::Or {
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs)
+                    }, span))
+                }));
+            }
+            PrecedenceGroup::Pipe => {
+                // like `parse_infix`, but the lhs of a pipe may also be a variable binding like `f as $x` or `f as [$x] ?// $x`
+                tf.retain(|tf_item| if let Tf::Token(Token::Whitespace, _) = *tf_item { false } else { true });
+                let mut idx = tf.len();
+                while idx > 0 { // iterate right-to-left because pipes are right-associative
+                    idx -= 1;
+                    let pipe_span = if let Tf::Token(Token::Pipe, pipe_span) = tf[idx] { pipe_span } else { continue; };
+                    let has_rhs = idx + 1 < tf.len() && if let Tf::Filter(_, _) = tf[idx + 1] { true } else { false };
+                    if !has_rhs { return Err(ParseErrorKind::MissingOperand(Token::Pipe).at(pipe_span)); }
+                    let (rhs, rhs_span) = if let Tf::Filter(rhs, rhs_span) = tf.remove(idx + 1) { (rhs, rhs_span) } else { unreachable!() };
+                    tf.remove(idx);
+                    // `label $name | body`
+                    let is_label = idx >= 2 && match (&tf[idx - 2], &tf[idx - 1]) {
+                        (&Tf::Token(ref token, _), &Tf::Filter(Filter::Variable(_), _)) => is_keyword(token, "label"),
+                        (_, _) => false
+                    };
+                    if is_label {
+                        let name = if let Tf::Filter(Filter::Variable(name), _) = tf.remove(idx - 1) { name } else { unreachable!() };
+                        idx -= 2;
+                        let label_span = tf[idx].span().to(rhs_span);
+                        tf[idx] = Tf::Filter(try_filter!(Filter::Label {
+                            name: name,
+                            body: Box::new(rhs)
+                        }, label_span), label_span);
+                        continue;
+                    }
+                    if let Some((source_idx, patterns, _)) = try!(take_patterns(&mut tf, idx)) {
+                        idx = source_idx;
+                        let (source, source_span) = if let Tf::Filter(source, source_span) = tf.remove(idx) { (source, source_span) } else { unreachable!() };
+                        tf.insert(idx, Tf::Filter(try_filter!(Filter::Bind {
+                            source: Box::new(source),
+                            patterns: patterns,
+                            body: Box::new(rhs)
+                        }, source_span.to(rhs_span)), source_span.to(rhs_span)));
+                    } else {
+                        if idx == 0 || if let Tf::Filter(_, _) = tf[idx - 1] { false } else { true } {
+                            return Err(ParseErrorKind::MissingOperand(Token::Pipe).at(pipe_span));
+                        }
+                        idx -= 1;
+                        let (lhs, lhs_span) = if let Tf::Filter(lhs, lhs_span) = tf.remove(idx) { (lhs, lhs_span) } else { unreachable!() };
+                        tf.insert(idx, Tf::Filter(try_filter!(Filter::Pipe {
+                            lhs: Box::new(lhs),
+                            rhs: Box::new(rhs)
+                        }, lhs_span.to(rhs_span)), lhs_span.to(rhs_span)));
+                    }
+                }
+                // a binding without a body, like `f as $x ;; $x`, binds its variables in the context for the code after `;;`
+                let end = if let Some(&Tf::Token(Token::AndThen(_), _)) = tf.last() { tf.len() - 1 } else { tf.len() };
+                if let Some((idx, patterns, patterns_span)) = try!(take_patterns(&mut tf, end)) {
+                    let (source, source_span) = if let Tf::Filter(source, source_span) = tf.remove(idx) { (source, source_span) } else { unreachable!() };
+                    tf.insert(idx, Tf::Filter(try_filter!(Filter::BindGlobal {
+                        source: Box::new(source),
+                        patterns: patterns
+                    }, source_span.to(patterns_span)), source_span.to(patterns_span)));
+                }
+            }
+            PrecedenceGroup::Postfix => {
+                try!(parse_postfix(&mut tf, &context));
+            }
         }
     }
     if tf.len() == 1 {
         match tf.pop() {
-            Some(Tf::Filter(result)) => Ok(result),
-            Some(Tf::Token(token)) => Err(ParseError::NotFullyParsed(vec![Tf::Token(token)])),
+            Some(Tf::Filter(result, _)) => Ok(result),
+            Some(Tf::Token(token, token_span)) => Err(ParseErrorKind::NotFullyParsed(vec![Tf::Token(token, token_span)]).at(token_span)),
             None => unreachable!()
         }
     } else {
-        Err(ParseError::NotFullyParsed(tf))
+        // point at the first token that could not be parsed, or at the second of two adjacent filters
+        let error_span = tf.iter().find(|tf_item| if let Tf::Token(_, _) = **tf_item { true } else { false }).or(tf.get(1)).map_or(span, Tf::span);
+        Err(ParseErrorKind::NotFullyParsed(tf).at(error_span))
+    }
+}
+
+#[test]
+fn test_index() {
+    use lang::value::Array;
+    use util::{run, run_with_input};
+
+    let input = Value::Array(Array::from((1..6).map(|i| Value::Number(BigRational::from_integer(FromPrimitive::from_i32(i).unwrap()))).collect::<Vec<_>>()));
+    for &(code, expected) in &[(".", "[1, 2, 3, 4, 5]"), (".[0]", "1"), (".[-1]", "5"), (".[7]", "null"), (".[1:3]", "[2, 3]"), (".[-2:]", "[4, 5]"), (".[:1]", "[1]"), (".foo", "raise \"type\" {\"message\": \"cannot index array with string\"}")] {
+        assert_eq!(run_with_input(code, input.clone()), vec![expected]);
+    }
+    // objects can only be indexed with strings, also when setting or destructuring
+    assert_eq!(run(r#"{"a": 1} | .["a"], .[0]"#), vec!["1", r#"raise "type" {"message": "cannot index object with number"}"#]);
+    assert_eq!(run("{} | setpath([null]; 1)"), vec![r#"raise "type" {"message": "cannot index object with null"}"#]);
+    assert_eq!(run("{} as [$a] | $a"), vec![r#"raise "type" {"message": "cannot index object with number"}"#]);
+}
+
+#[test]
+fn test_literals() {
+    use builtin;
+    use util::run;
+
+    for &(code, expected) in &[("1e-3", "1/1000"), ("-1.25", "-5/4"), ("1.5E2", "150"), ("0.1", "1/10"), ("true", "true"), ("null", "null"), (r#""a\"\u0041\n""#, "\"a\\\"A\\n\"")] {
+        assert_eq!(run(code), vec![expected]);
+    }
+    // exponents which would take too long to compute are rejected
+    assert_eq!(run("1e-1000"), vec![format!("1/1{}", "0".repeat(1000))]);
+    assert!(parse("1e999999999", builtin::context()).is_err());
+    assert_eq!(parse_number("1e-999999999"), None);
+}
+
+#[test]
+fn test_interpolation() {
+    use util::run;
+
+    for &(code, ref expected) in &[
+        (r#""a\(1, 2)b\("c")""#, vec![r#""a1bc""#, r#""a2bc""#]),
+        (r#""\("\([1])")""#, vec![r#""[1]""#]),
+        (r#""\(1, 2)\(3, 4)-\(5)""#, vec![r#""13-5""#, r#""23-5""#, r#""14-5""#, r#""24-5""#]),
+        // the first interpolation is streamed, so it may be infinite
+        (r#"first("a\(repeat(1))b"), [limit(3; "\(range(1000000000)) \(0, 1)")]"#, vec![r#""anullb""#, r#"["0 0", "1 0", "2 0"]"#]),
+        (r#"[1, "a b"] | @sh "echo \(.[]) \(.)""#, vec![r#""echo 1 1 'a b'""#, r#""echo 'a b' 1 'a b'""#]),
+        (r#"[1, "a,\"b"] | @csv, @tsv"#, vec![r#""1,\"a,\"\"b\"""#, r#""1\ta,\"b""#]),
+        (r#""é" | @base64 | ., @base64d"#, vec![r#""w6k=""#, r#""é""#]),
+        // values other than strings are formatted as compact JSON, with numbers in decimal notation
+        (r#"1.5, [0.5, "\u0001"] | @json"#, vec![r#""1.5""#, r#""[0.5,\"\\u0001\"]""#]),
+        (r#"[0.5, 1] | @csv, "\(0.25) \(1 / 3) \(-1e-7)""#, vec![r#""0.5,1""#, r#""0.25 0.33333333333333333 -1e-7""#])
+    ] {
+        assert_eq!(run(code), *expected);
+    }
+}
+
+#[test]
+fn test_calls() {
+    use builtin;
+    use util::run;
+
+    for &(code, expected) in &[("def f(g; $x): g + x + $x; f(1; 2)", "5"), ("def fac: if . <= 1 then 1 else . * (. - 1 | fac) end; 5 | fac", "120"), ("def f(x): def g: x; g; f(4)", "4")] {
+        assert_eq!(run(code), vec![expected]);
     }
+    for &(code, name, arity) in &[("foo", "foo", 0), ("map(1; 2)", "map", 2), ("mod::f(1)", "mod::f", 1), ("(def g: 1; g), g", "g", 0)] {
+        match parse(code, builtin::context()) {
+            Err(ParseError { kind: ParseErrorKind::UnknownFunction(ref err_name, err_arity), .. }) => { assert_eq!((String::from(err_name), err_arity), (name.to_owned(), arity)); }
+            result => panic!("expected UnknownFunction for {:?}, got {:?}", code, result)
+        }
+    }
+}
+
+#[test]
+fn test_error_spans() {
+    use builtin;
+
+    let err = parse("1 +\n  [2, foo]", builtin::context()).unwrap_err();
+    assert_eq!((err.span.start.line, err.span.start.column, err.span.end.column), (1, 6, 9));
+    assert_eq!(err.to_string(), "foo/0 is not defined at line 2, column 7\n  [2, foo]\n      ^^^");
+    let err = parse(". | (1 + 2", builtin::context()).unwrap_err();
+    assert_eq!((err.span.start.line, err.span.start.column), (0, 4));
+    let err = parse("\"é\" + foo", builtin::context()).unwrap_err();
+    assert_eq!((err.span.start.offset, err.span.start.byte_offset, err.span.end.byte_offset), (6, 7, 10));
 }
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use unicode::UString;
+
+use lang::Value;
+use lang::value::Function;
+
+/// The variables bound by enclosing `as` bindings, by name without the `$`, and the functions defined by enclosing `def`s, by name and arity.
+///
+/// Labels of enclosing `label` filters are also kept here, separately from variables. Each time a `label` filter runs, its label gets a new unique id, which identifies the `break`s targeting it.
+///
+/// A scope is not part of the `Context`. Instead, it is applied to a filter using `Filter::bind`, which replaces the references to its variables with their values, and calls to its functions with the functions themselves.
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    variables: HashMap<UString, Value>,
+    functions: HashMap<(UString, usize), Function>,
+    labels: HashMap<UString, usize>
+}
+
+impl Scope {
+    /// Returns the value of the variable with the given name, if it is bound in this scope.
+    pub fn get(&self, name: &UString) -> Option<&Value> {
+        self.variables.get(name)
+    }
+
+    /// Binds a variable, replacing any previous binding with the same name.
+    pub fn insert(&mut self, name: UString, value: Value) {
+        self.variables.insert(name, value);
+    }
+
+    /// Returns the function with the given name and arity, if it is defined in this scope.
+    pub fn get_function(&self, name: &UString, arity: usize) -> Option<&Function> {
+        self.functions.get(&(name.clone(), arity))
+    }
+
+    /// Defines a function, replacing any previous definition with the same name and arity.
+    pub fn insert_function(&mut self, function: Function) {
+        self.functions.insert((function.name.clone(), function.arity()), function);
+    }
+
+    /// Returns the id of the label with the given name, if it is bound in this scope.
+    pub fn get_label(&self, name: &UString) -> Option<usize> {
+        self.labels.get(name).cloned()
+    }
+
+    /// Binds a label, replacing any previous binding with the same name.
+    pub fn insert_label(&mut self, name: UString, id: usize) {
+        self.labels.insert(name, id);
+    }
+
+    /// Returns a copy of this scope with the given label removed, for use inside a `label` filter which shadows it.
+    pub fn without_label(&self, name: &UString) -> Scope {
+        let mut result = self.clone();
+        result.labels.remove(name);
+        result
+    }
+
+    /// Returns a copy of this scope with the given variables and functions removed, for use inside bindings and definitions which shadow them.
+    pub fn without(&self, variables: &[UString], functions: &[(UString, usize)]) -> Scope {
+        let mut result = self.clone();
+        for name in variables {
+            result.variables.remove(name);
+        }
+        for key in functions {
+            result.functions.remove(key);
+        }
+        result
+    }
+}
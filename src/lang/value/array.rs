@@ -1,4 +1,4 @@
-use std::hash;
+use std::{hash, mem, vec};
 use std::iter::FromIterator;
 use std::sync::mpsc;
 
@@ -20,12 +20,42 @@ impl<'a, T> Array<T> {
         self.buffer.get(idx)
     }
 
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut T> {
+        self.buffer.get_mut(idx)
+    }
+
+    /// Replaces the item at `idx`, returning the old one. Panics if `idx` is out of bounds.
+    pub fn set(&mut self, idx: usize, item: T) -> T {
+        mem::replace(&mut self.buffer[idx], item)
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.buffer.push(item);
+    }
+
+    /// Removes and returns the item at `idx`, shifting the following items to the left. Panics if `idx` is out of bounds.
+    pub fn remove(&mut self, idx: usize) -> T {
+        self.buffer.remove(idx)
+    }
+
+    /// Replaces the items from `start` up to but excluding `end` with `items`.
+    pub fn splice<I: IntoIterator<Item = T>>(&mut self, start: usize, end: usize, items: I) {
+        let tail = self.buffer.split_off(end);
+        self.buffer.truncate(start);
+        self.buffer.extend(items);
+        self.buffer.extend(tail);
+    }
+
     pub fn iter(&'a self) -> Iter<'a, T> {
         Iter {
             array: self,
             index: 0
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
 }
 
 impl<T> Default for Array<T> {
@@ -42,7 +72,7 @@ impl<T> From<Vec<T>> for Array<T> {
 
 impl From<Receiver> for Array<Value> {
     fn from(rx: Receiver) -> Array<Value> {
-        Array { buffer: rx.values.into_iter().collect() } //TODO implement lazy arrays
+        Array { buffer: rx.into_iter().collect() } //TODO implement lazy arrays
     }
 }
 
@@ -53,7 +83,7 @@ impl<T, U> FromIterator<T> for Array<U> where U: From<T> {
 }
 
 pub struct IntoIter<T> {
-    buffer: Vec<T>,
+    buffer: vec::IntoIter<T>,
     channel: mpsc::Receiver<T>
 }
 
@@ -61,11 +91,7 @@ impl<T> Iterator for IntoIter<T> {
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
-        if self.buffer.len() > 0 {
-            Some(self.buffer.remove(0))
-        } else {
-            self.channel.recv().ok()
-        }
+        self.buffer.next().or_else(|| self.channel.recv().ok())
     }
 }
 
@@ -90,7 +116,7 @@ impl<T> IntoIterator for Array<T> {
 
     fn into_iter(self) -> IntoIter<T> {
         IntoIter {
-            buffer: self.buffer,
+            buffer: self.buffer.into_iter(),
             channel: mpsc::channel().1 //TODO use the array's channel
         }
     }
@@ -0,0 +1,60 @@
+use std::fmt;
+use std::sync::Arc;
+
+use unicode::UString;
+
+use lang::filter::Filter;
+
+/// A parameter of a function definition.
+#[derive(Clone, Debug)]
+pub enum Param {
+    /// A filter parameter like the `f` in `def map(f): [.[] | f];`, which is called like a function without arguments.
+    Filter(UString),
+    /// A value parameter like the `$n` in `def inc($n): . + $n;`, which is bound both as a variable and as a function without arguments.
+    Value(UString)
+}
+
+impl Param {
+    /// The name of the parameter, without the `$`.
+    pub fn name(&self) -> &UString {
+        match *self {
+            Param::Filter(ref name) | Param::Value(ref name) => name
+        }
+    }
+}
+
+/// A function defined using `def`.
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub name: UString,
+    pub params: Vec<Param>,
+    /// The function body. References to variables and functions from the scope of the definition have already been replaced, but calls to the function itself and to its parameters have not.
+    pub body: Arc<Filter>
+}
+
+impl Function {
+    /// The number of parameters. Functions with the same name but different arities are different functions.
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(w, "def {}", String::from(&self.name)));
+        if self.params.len() > 0 {
+            try!(write!(w, "("));
+            for (i, param) in self.params.iter().enumerate() {
+                if i > 0 {
+                    try!(write!(w, "; "));
+                }
+                if let Param::Value(_) = *param {
+                    try!(write!(w, "$"));
+                }
+                try!(write!(w, "{}", String::from(param.name())));
+            }
+            try!(write!(w, ")"));
+        }
+        write!(w, ": ...;")
+    }
+}
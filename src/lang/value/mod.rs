@@ -1,31 +1,46 @@
 pub mod array;
+pub mod function;
 pub mod object;
 
 pub use self::array::Array;
+pub use self::function::Function;
 pub use self::object::Object;
 
-use std::{fmt, hash, string};
+use std::{fmt, hash, iter, mem, string};
+use std::cmp::Ordering;
 use std::iter::FromIterator;
 
-use num::BigRational;
+use num::{self, BigInt, BigRational, FromPrimitive, Signed, ToPrimitive};
 
 use unicode::UString;
 
+/// The largest index at which `setpath` adds an item to an array, padding it with `null`s. Larger indices raise an exception instead of trying to allocate all of the padding.
+const MAX_ARRAY_INDEX: usize = 1 << 24;
+
+/// The name of an exception, which tells apart the exceptions raised by code from the signal used by `break`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ExceptionName {
+    /// An exception raised with `raise` or by a builtin function, which can be caught by `try`, `//`, and `?//`.
+    Raised(UString),
+    /// The signal sent by `break $name` to the run of `label $name` with the given id. It can't be raised from code, so nothing but its label stops it.
+    Break(usize)
+}
+
 #[derive(Clone, Debug)]
 pub enum Value {
-    Exception(UString, Object<HashableValue, Value>),
+    Exception(ExceptionName, Object<HashableValue, Value>),
     Null,
     Boolean(bool),
     Number(BigRational),
     String(UString),
     Array(Array<Value>),
     Object(Object<HashableValue, Value>),
-    Function //TODO Function(Function)
+    Function(Function)
 }
 
 #[derive(Clone, Debug)]
 pub enum HashableValue {
-    Exception(UString, Object<HashableValue, Value>),
+    Exception(ExceptionName, Object<HashableValue, Value>),
     Null,
     Boolean(bool),
     Number(BigRational),
@@ -34,13 +49,471 @@ pub enum HashableValue {
     Object(Object<HashableValue, HashableValue>)
 }
 
+impl Value {
+    /// Creates an exception with the given name and metadata.
+    pub fn exception<S: Into<UString>>(name: S, meta: Vec<(&str, Value)>) -> Value {
+        Value::Exception(ExceptionName::Raised(name.into()), meta.into_iter().map(|(k, v)| (HashableValue::String(UString::from(k)), v)).collect())
+    }
+
+    /// Shorthand for a `type` exception with the given message.
+    pub fn type_error<S: Into<string::String>>(message: S) -> Value {
+        Value::exception("type", vec![("message", Value::String(UString::from(message.into())))])
+    }
+
+    /// Returns false for `false` and `null`, true for everything else.
+    pub fn is_truthy(&self) -> bool {
+        match *self {
+            Value::Null | Value::Boolean(false) => false,
+            _ => true
+        }
+    }
+
+    /// The value as compact JSON, like `tojson`.
+    ///
+    /// Exceptions and functions, which have no JSON representation, become strings of how they are shown in the REPL, and so do object keys which are not strings.
+    pub fn to_json(&self) -> string::String {
+        match *self {
+            Value::Null => "null".to_owned(),
+            Value::Boolean(b) => b.to_string(),
+            Value::Number(ref n) => json_number(n),
+            Value::String(ref s) => json_string(&string::String::from(s)),
+            Value::Array(ref a) => format!("[{}]", a.iter().map(Value::to_json).collect::<Vec<_>>().join(",")),
+            Value::Object(ref o) => {
+                let entries = o.iter().map(|(k, v)| {
+                    let key = if let HashableValue::String(ref k) = *k { json_string(&string::String::from(k)) } else { json_string(&Value::from(k).to_json()) };
+                    format!("{}:{}", key, v.to_json())
+                }).collect::<Vec<_>>();
+                format!("{{{}}}", entries.join(","))
+            }
+            Value::Exception(_, _) | Value::Function(_) => json_string(&self.to_string())
+        }
+    }
+
+    /// The position of the value's type in the total order of values.
+    fn type_rank(&self) -> u8 {
+        match *self {
+            Value::Exception(_, _) => 0,
+            Value::Null => 1,
+            Value::Boolean(false) => 2,
+            Value::Boolean(true) => 3,
+            Value::Number(_) => 4,
+            Value::String(_) => 5,
+            Value::Array(_) => 6,
+            Value::Object(_) => 7,
+            Value::Function(_) => 8
+        }
+    }
+
+    /// The name of the value's type, as used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Exception(_, _) => "exception",
+            Value::Null => "null",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+            Value::Function(_) => "function"
+        }
+    }
+
+    /// Converts the value into a `HashableValue`, or returns `None` if it contains a function.
+    pub fn to_hashable(&self) -> Option<HashableValue> {
+        match *self {
+            Value::Exception(ref name, ref meta) => Some(HashableValue::Exception(name.clone(), meta.clone())),
+            Value::Null => Some(HashableValue::Null),
+            Value::Boolean(b) => Some(HashableValue::Boolean(b)),
+            Value::Number(ref n) => Some(HashableValue::Number(n.clone())),
+            Value::String(ref s) => Some(HashableValue::String(s.clone())),
+            Value::Array(ref a) => {
+                let mut items = vec![];
+                for item in a {
+                    if let Some(item) = item.to_hashable() {
+                        items.push(item);
+                    } else {
+                        return None;
+                    }
+                }
+                Some(HashableValue::Array(Array::from(items)))
+            }
+            Value::Object(ref o) => {
+                let mut result = Object::default();
+                for (k, v) in o {
+                    if let Some(v) = v.to_hashable() {
+                        result.insert(k.clone(), v);
+                    } else {
+                        return None;
+                    }
+                }
+                Some(HashableValue::Object(result))
+            }
+            Value::Function(_) => None
+        }
+    }
+
+    /// Looks up `index` in `self`, as in `.[index]`.
+    ///
+    /// Exceptions in either operand are propagated, and indexing into `null` always produces `null`.
+    pub fn index(&self, index: &Value) -> Value {
+        match (self, index) {
+            (&Value::Exception(_, _), _) => self.clone(),
+            (_, &Value::Exception(_, _)) => index.clone(),
+            (&Value::Null, &Value::Null) |
+            (&Value::Null, &Value::Number(_)) |
+            (&Value::Null, &Value::String(_)) => Value::Null,
+            (&Value::Array(_), &Value::Number(_)) |
+            (&Value::Object(_), &Value::String(_)) => self.index_ref(index).map_or(Value::Null, |v| v.clone()),
+            (_, _) => Value::type_error(format!("cannot index {} with {}", self.type_name(), index.type_name()))
+        }
+    }
+
+    /// Returns a reference to the array item or object value at `index`, or `None` if it is missing or `self` is not indexed by references.
+    fn index_ref(&self, index: &Value) -> Option<&Value> {
+        match (self, index) {
+            (&Value::Array(ref a), &Value::Number(ref n)) => {
+                let len = BigInt::from_usize(a.len()).expect("array length out of range");
+                let mut idx = n.floor().to_integer();
+                if idx.is_negative() {
+                    idx = idx + len;
+                }
+                idx.to_usize().and_then(|idx| a.get(idx))
+            }
+            (&Value::Object(ref o), &Value::String(ref key)) => o.get(&HashableValue::String(key.clone())),
+            (_, _) => None
+        }
+    }
+
+    /// Returns the part of `self` between `from` and `to`, as in `.[from:to]`.
+    ///
+    /// Bounds may be `null` to slice from the start or to the end, and negative bounds count from the end.
+    pub fn slice(&self, from: &Value, to: &Value) -> Value {
+        let len = match *self {
+            Value::Exception(_, _) | Value::Null => { return self.clone(); }
+            Value::String(ref s) => s.len(),
+            Value::Array(ref a) => a.len(),
+            _ => { return Value::type_error(format!("cannot slice {}", self.type_name())); }
+        };
+        let (start, end) = match slice_range(len, from, to) { Ok(range) => range, Err(e) => { return e; } };
+        match *self {
+            Value::String(ref s) => Value::String((start..end).map(|i| s[i]).collect()),
+            Value::Array(ref a) => Value::Array(a.iter().skip(start).take(end - start).cloned().collect()),
+            _ => unreachable!()
+        }
+    }
+
+    /// Looks up a single path component in `self`, which is either an index as in `.[index]`, or a slice like `{"start": 1, "end": null}` as in `.[1:]`.
+    fn path_component(&self, component: &Value) -> Value {
+        match (self, slice_bounds(component)) {
+            (&Value::Null, Some((from, to))) |
+            (&Value::String(_), Some((from, to))) |
+            (&Value::Array(_), Some((from, to))) => self.slice(&from, &to),
+            (_, _) => self.index(component)
+        }
+    }
+
+    /// Returns the value at `path` in `self`, as in `getpath(path)`. Missing parts of the path produce `null`.
+    pub fn getpath(&self, path: &[Value]) -> Value {
+        let mut current = self;
+        for (i, component) in path.iter().enumerate() {
+            match *current {
+                Value::Null => { return Value::Null; }
+                Value::Exception(_, _) => { return current.clone(); }
+                _ => {}
+            }
+            // only the value at the end of the path is cloned, unless a component like a slice produces a new value
+            match current.index_ref(component) {
+                Some(child) => { current = child; }
+                None => { return current.path_component(component).getpath(&path[i + 1..]); }
+            }
+        }
+        current.clone()
+    }
+
+    /// Returns a copy of `self` with the value at `path` replaced by `value`, as in `setpath(path; value)`.
+    ///
+    /// Missing parts of the path are created: `null` becomes an object or array, depending on the type of the path component, and arrays are padded with `null`.
+    pub fn setpath(self, path: &[Value], value: Value) -> Value {
+        let (component, rest) = match path.split_first() {
+            Some(split) => split,
+            None => { return value; }
+        };
+        if let Value::Exception(_, _) = *component {
+            return component.clone();
+        }
+        match (self, slice_bounds(component)) {
+            (Value::Exception(name, meta), _) => Value::Exception(name, meta),
+            (Value::Null, Some(_)) => Value::Array(Array::new()).setpath(path, value),
+            (Value::Array(mut array), Some((from, to))) => {
+                let (start, end) = match slice_range(array.len(), &from, &to) { Ok(range) => range, Err(e) => { return e; } };
+                let slice = Value::Array(array.iter().skip(start).take(end - start).cloned().collect());
+                match slice.setpath(rest, value) {
+                    Value::Array(items) => {
+                        array.splice(start, end, items);
+                        Value::Array(array)
+                    }
+                    Value::Exception(name, meta) => Value::Exception(name, meta),
+                    items => Value::type_error(format!("a slice of an array can only be assigned an array, not {}", items.type_name()))
+                }
+            }
+            (Value::Null, None) => {
+                if let Value::Number(_) = *component {
+                    Value::Array(Array::new()).setpath(path, value)
+                } else {
+                    Value::Object(Object::default()).setpath(path, value)
+                }
+            }
+            (Value::Array(mut array), None) => {
+                let n = if let Value::Number(ref n) = *component { n } else {
+                    return Value::type_error(format!("cannot index array with {}", component.type_name()));
+                };
+                let mut idx = n.floor().to_integer();
+                if idx.is_negative() {
+                    idx = idx + BigInt::from_usize(array.len()).expect("array length out of range");
+                }
+                let idx = if let Some(idx) = idx.to_usize() { idx } else {
+                    return Value::type_error("array index out of bounds");
+                };
+                if idx >= array.len() && idx > MAX_ARRAY_INDEX {
+                    return Value::type_error("array index too large");
+                }
+                while array.len() <= idx {
+                    array.push(Value::Null);
+                }
+                let item = array.set(idx, Value::Null).setpath(rest, value);
+                if let Value::Exception(_, _) = item {
+                    return item;
+                }
+                array.set(idx, item);
+                Value::Array(array)
+            }
+            (Value::Object(mut object), _) => {
+                let key = if let Value::String(ref key) = *component { HashableValue::String(key.clone()) } else {
+                    return Value::type_error(format!("cannot index object with {}", component.type_name()));
+                };
+                {
+                    let entry = object.get_or_insert_with(key, || Value::Null);
+                    let item = mem::replace(entry, Value::Null).setpath(rest, value);
+                    if let Value::Exception(_, _) = item {
+                        return item;
+                    }
+                    *entry = item;
+                }
+                Value::Object(object)
+            }
+            (target, _) => Value::type_error(format!("cannot index {} with {}", target.type_name(), component.type_name()))
+        }
+    }
+
+    /// Returns a copy of `self` with the values at all of the `paths` removed, as in `delpaths(paths)`.
+    ///
+    /// The paths are deleted starting with the greatest, so that removing an array item does not change the meaning of the other paths.
+    pub fn delpaths(self, mut paths: Vec<Vec<Value>>) -> Value {
+        paths.sort();
+        paths.dedup();
+        let mut result = self;
+        for path in paths.iter().rev() {
+            result = result.delpath(path);
+            if let Value::Exception(_, _) = result {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Returns a copy of `self` with the value at `path` removed. Deleting the empty path produces `null`.
+    fn delpath(self, path: &[Value]) -> Value {
+        let (component, rest) = match path.split_first() {
+            Some(split) => split,
+            None => { return Value::Null; }
+        };
+        if let Value::Exception(_, _) = *component {
+            return component.clone();
+        }
+        match (self, slice_bounds(component)) {
+            (Value::Exception(name, meta), _) => Value::Exception(name, meta),
+            (Value::Null, _) => Value::Null,
+            (Value::Array(mut array), Some((from, to))) => {
+                let (start, end) = match slice_range(array.len(), &from, &to) { Ok(range) => range, Err(e) => { return e; } };
+                if rest.is_empty() {
+                    array.splice(start, end, vec![]);
+                } else {
+                    match Value::Array(array.iter().skip(start).take(end - start).cloned().collect()).delpath(rest) {
+                        Value::Array(items) => { array.splice(start, end, items); }
+                        Value::Exception(name, meta) => { return Value::Exception(name, meta); }
+                        _ => unreachable!()
+                    }
+                }
+                Value::Array(array)
+            }
+            (Value::Array(mut array), None) => {
+                let n = if let Value::Number(ref n) = *component { n } else {
+                    return Value::type_error(format!("cannot delete {} index of array", component.type_name()));
+                };
+                let mut idx = n.floor().to_integer();
+                if idx.is_negative() {
+                    idx = idx + BigInt::from_usize(array.len()).expect("array length out of range");
+                }
+                match idx.to_usize() {
+                    Some(idx) if idx < array.len() => {
+                        if rest.is_empty() {
+                            array.remove(idx);
+                        } else {
+                            let item = array.set(idx, Value::Null).delpath(rest);
+                            if let Value::Exception(_, _) = item {
+                                return item;
+                            }
+                            array.set(idx, item);
+                        }
+                    }
+                    _ => {} // deleting a missing item does nothing
+                }
+                Value::Array(array)
+            }
+            (Value::Object(mut object), _) => {
+                let key = if let Value::String(ref key) = *component { HashableValue::String(key.clone()) } else {
+                    return Value::type_error(format!("cannot delete {} index of object", component.type_name()));
+                };
+                if rest.is_empty() {
+                    object.remove(&key);
+                } else if let Some(entry) = object.get_mut(&key) {
+                    let item = mem::replace(entry, Value::Null).delpath(rest);
+                    if let Value::Exception(_, _) = item {
+                        return item;
+                    }
+                    *entry = item;
+                }
+                Value::Object(object)
+            }
+            (target, _) => Value::type_error(format!("cannot delete {} index of {}", component.type_name(), target.type_name()))
+        }
+    }
+}
+
+/// If `component` is a slice path component like `{"start": 1, "end": null}`, returns its bounds.
+fn slice_bounds(component: &Value) -> Option<(Value, Value)> {
+    if let Value::Object(ref object) = *component {
+        let start = object.get(&HashableValue::String(UString::from("start")));
+        let end = object.get(&HashableValue::String(UString::from("end")));
+        if let (Some(start), Some(end), 2) = (start, end, object.len()) {
+            return Some((start.clone(), end.clone()));
+        }
+    }
+    None
+}
+
+/// Converts the bounds of a slice into a range of indices of a string or array of length `len`.
+fn slice_range(len: usize, from: &Value, to: &Value) -> Result<(usize, usize), Value> {
+    fn bound(b: &Value, len: usize, default: usize, round_up: bool) -> Result<usize, Value> {
+        match *b {
+            Value::Null => Ok(default),
+            Value::Number(ref n) => {
+                let big_len = BigInt::from_usize(len).expect("slice length out of range");
+                let mut idx = if round_up { n.ceil() } else { n.floor() }.to_integer();
+                if idx.is_negative() {
+                    idx = idx + big_len.clone();
+                }
+                Ok(if idx.is_negative() { 0 } else if idx > big_len { len } else { idx.to_usize().unwrap() })
+            }
+            Value::Exception(_, _) => Err(b.clone()),
+            _ => Err(Value::type_error(format!("cannot use {} as a slice index", b.type_name())))
+        }
+    }
+
+    let start = try!(bound(from, len, 0, false));
+    let end = try!(bound(to, len, len, true));
+    Ok((start, if end < start { start } else { end }))
+}
+
+/// The number of significant digits with which numbers that are not integers are written as JSON, which is enough to tell apart any two doubles.
+const JSON_SIGNIFICANT_DIGITS: usize = 17;
+
+/// Writes a number in JSON's decimal notation. Integers are written exactly, other numbers are rounded to `JSON_SIGNIFICANT_DIGITS` significant digits.
+fn json_number(n: &BigRational) -> string::String {
+    if n.is_integer() { return n.to_integer().to_string(); }
+    let numer = n.numer().abs();
+    let denom = n.denom().abs();
+    let ten = BigInt::from_u32(10).unwrap();
+    // the number multiplied by 10^scale, as a fraction
+    let scaled = |scale: i64| if scale >= 0 {
+        (&numer * num::pow(ten.clone(), scale as usize), denom.clone())
+    } else {
+        (numer.clone(), &denom * num::pow(ten.clone(), -scale as usize))
+    };
+    // find the scale at which the integer part has the right number of digits, starting from an estimate based on the lengths of numerator and denominator
+    let mut scale = JSON_SIGNIFICANT_DIGITS as i64 - numer.to_string().len() as i64 + denom.to_string().len() as i64;
+    loop {
+        let (scaled_numer, scaled_denom) = scaled(scale);
+        let len = (scaled_numer / scaled_denom).to_string().len();
+        if len < JSON_SIGNIFICANT_DIGITS {
+            scale += 1;
+        } else if len > JSON_SIGNIFICANT_DIGITS {
+            scale -= 1;
+        } else {
+            break;
+        }
+    }
+    let (scaled_numer, scaled_denom) = scaled(scale);
+    let two = BigInt::from_u32(2).unwrap();
+    let mut digits = ((scaled_numer * &two + &scaled_denom) / (scaled_denom * &two)).to_string(); // rounded to the nearest integer
+    while digits.ends_with('0') {
+        digits.pop();
+        scale -= 1;
+    }
+    let point = digits.len() as i64 - scale; // the position of the decimal point in the digits
+    let mut result = if n.is_negative() { "-".to_owned() } else { string::String::new() };
+    if point > 0 && point <= JSON_SIGNIFICANT_DIGITS as i64 {
+        if point as usize >= digits.len() {
+            // rounded to an integer
+            result.push_str(&digits);
+            result.extend(iter::repeat('0').take(point as usize - digits.len()));
+        } else {
+            result.push_str(&format!("{}.{}", &digits[..point as usize], &digits[point as usize..]));
+        }
+    } else if point <= 0 && point > -6 {
+        result.push_str("0.");
+        result.extend(iter::repeat('0').take(-point as usize));
+        result.push_str(&digits);
+    } else {
+        result.push_str(&digits[..1]);
+        if digits.len() > 1 {
+            result.push_str(&format!(".{}", &digits[1..]));
+        }
+        result.push_str(&format!("e{}", point - 1));
+    }
+    result
+}
+
+/// Writes a string as a JSON string literal, escaping quotes, backslashes, and control characters.
+fn json_string(s: &str) -> string::String {
+    let mut result = string::String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => { result.push_str("\\\""); }
+            '\\' => { result.push_str("\\\\"); }
+            '\n' => { result.push_str("\\n"); }
+            '\r' => { result.push_str("\\r"); }
+            '\t' => { result.push_str("\\t"); }
+            '\u{8}' => { result.push_str("\\b"); }
+            '\u{c}' => { result.push_str("\\f"); }
+            c if c < ' ' || c == '\u{7f}' => { result.push_str(&format!("\\u{:04x}", c as u32)); }
+            c => { result.push(c); }
+        }
+    }
+    result.push('"');
+    result
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
         use self::Value::*;
 
         match *self {
             Exception(ref name, ref meta) => {
-                try!(write!(w, "raise {:?}", string::String::from(name)));
+                match *name {
+                    ExceptionName::Raised(ref name) => { try!(write!(w, "raise {:?}", string::String::from(name))); }
+                    ExceptionName::Break(id) => { try!(write!(w, "break <label {}>", id)); }
+                }
                 if meta.len() > 0 {
                     try!(write!(w, " {{"));
                     for (i, (k, v)) in meta.iter().enumerate() {
@@ -64,7 +537,7 @@ impl fmt::Display for Value {
                 try!(write!(w, "{}", n));
             }
             String(ref s) => {
-                try!(write!(w, "{:?}", string::String::from(s)));
+                try!(write!(w, "{}", json_string(&string::String::from(s))));
             }
             Array(ref a) => {
                 try!(write!(w, "["));
@@ -88,14 +561,84 @@ impl fmt::Display for Value {
                 }
                 try!(write!(w, "}}"));
             }
-            Function => {
-                try!(write!(w, "def (...)"));
+            Function(ref f) => {
+                try!(f.fmt(w));
             }
         }
         Ok(())
     }
 }
 
+/// Compares strings by their code points.
+fn cmp_strings(l: &UString, r: &UString) -> Ordering {
+    (0..l.len()).map(|i| l[i]).cmp((0..r.len()).map(|i| r[i]))
+}
+
+/// Compares exception names, with raised exceptions sorted by name before `break` signals.
+fn cmp_exception_names(l: &ExceptionName, r: &ExceptionName) -> Ordering {
+    match (l, r) {
+        (&ExceptionName::Raised(ref l), &ExceptionName::Raised(ref r)) => cmp_strings(l, r),
+        (&ExceptionName::Raised(_), &ExceptionName::Break(_)) => Ordering::Less,
+        (&ExceptionName::Break(_), &ExceptionName::Raised(_)) => Ordering::Greater,
+        (&ExceptionName::Break(l), &ExceptionName::Break(r)) => l.cmp(&r)
+    }
+}
+
+/// Compares objects by their sorted keys first, then by the values of each key in order.
+fn cmp_objects(l: &Object<HashableValue, Value>, r: &Object<HashableValue, Value>) -> Ordering {
+    let mut l_keys = l.iter().map(|(k, _)| Value::from(k)).collect::<Vec<_>>();
+    let mut r_keys = r.iter().map(|(k, _)| Value::from(k)).collect::<Vec<_>>();
+    l_keys.sort();
+    r_keys.sort();
+    match l_keys.cmp(&r_keys) {
+        Ordering::Equal => {}
+        ordering => { return ordering; }
+    }
+    for key in l_keys {
+        let key = key.to_hashable().expect("object key is not hashable");
+        match l.get(&key).cmp(&r.get(&key)) {
+            Ordering::Equal => {}
+            ordering => { return ordering; }
+        }
+    }
+    Ordering::Equal
+}
+
+/// Values are totally ordered as in jq: `null < false < true < numbers < strings < arrays < objects`. Exceptions are sorted first, and functions last, by name and arity.
+impl Ord for Value {
+    fn cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (&Value::Exception(ref nl, ref ml), &Value::Exception(ref nr, ref mr)) => match cmp_exception_names(nl, nr) {
+                Ordering::Equal => cmp_objects(ml, mr),
+                ordering => ordering
+            },
+            (&Value::Number(ref l), &Value::Number(ref r)) => l.cmp(r),
+            (&Value::String(ref l), &Value::String(ref r)) => cmp_strings(l, r),
+            (&Value::Array(ref l), &Value::Array(ref r)) => l.iter().cmp(r.iter()),
+            (&Value::Object(ref l), &Value::Object(ref r)) => cmp_objects(l, r),
+            (&Value::Function(ref l), &Value::Function(ref r)) => match cmp_strings(&l.name, &r.name) {
+                Ordering::Equal => l.arity().cmp(&r.arity()),
+                ordering => ordering
+            },
+            (_, _) => self.type_rank().cmp(&other.type_rank())
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
 impl fmt::Display for HashableValue {
     fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
         Value::from(self).fmt(w)
@@ -221,3 +764,19 @@ fn test_values() {
     array_map.insert(Array::from(vec![HashableValue::Boolean(false), HashableValue::Null]), "test 1");
     array_map.insert(Array::new(), "test 2");
 }
+
+#[test]
+fn test_ordering() {
+    let mut values = vec![
+        Value::Object(Object::default()),
+        Value::Array(Array::new()),
+        Value::String(UString::from("a")),
+        Value::Number(BigRational::from_integer(BigInt::from_i32(-1).unwrap())),
+        Value::Boolean(true),
+        Value::Boolean(false),
+        Value::Null
+    ];
+    let expected = values.iter().rev().cloned().collect::<Vec<_>>();
+    values.sort();
+    assert_eq!(values, expected);
+}
@@ -8,6 +8,14 @@ pub struct Object<K: Eq, V> {
 }
 
 impl<K: Eq, V> Object<K, V> {
+    pub fn get(&self, k: &K) -> Option<&V> {
+        self.buffer.iter().find(|&&(ref key, _)| key == k).map(|&(_, ref v)| v)
+    }
+
+    pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        self.buffer.iter_mut().find(|&&mut (ref key, _)| key == k).map(|&mut (_, ref mut v)| v)
+    }
+
     pub fn get_idx(&self, idx: usize) -> Option<(&K, &V)> {
         if self.buffer.len() > idx {
             let (ref k, ref v) = self.buffer[idx];
@@ -27,6 +35,25 @@ impl<K: Eq, V> Object<K, V> {
         None
     }
 
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        if let Some(idx) = self.buffer.iter().position(|&(ref key, _)| key == k) {
+            Some(self.buffer.remove(idx).1)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the value for `k`, inserting the result of `default` first if there is none.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, k: K, default: F) -> &mut V {
+        let idx = if let Some(idx) = self.buffer.iter().position(|&(ref key, _)| *key == k) {
+            idx
+        } else {
+            self.buffer.push((k, default()));
+            self.buffer.len() - 1
+        };
+        &mut self.buffer[idx].1
+    }
+
     pub fn iter(&self) -> Iter<K, V> {
         Iter {
             object: self,
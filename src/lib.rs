@@ -5,6 +5,7 @@ extern crate chan;
 extern crate eventual;
 extern crate itertools;
 extern crate num;
+extern crate regex;
 extern crate unicode;
 
 pub mod builtin;
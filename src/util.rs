@@ -1,5 +1,7 @@
+use lang::Context;
 use lang::channel::{Sender, Receiver};
 use lang::filter::Filter;
+use lang::value::Value;
 
 use std::fmt;
 use std::ops::{Deref, DerefMut};
@@ -18,6 +20,10 @@ impl<T> Labeled<T> {
             value: value
         }
     }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
 }
 
 impl<T> From<T> for Labeled<T> {
@@ -46,4 +52,25 @@ impl<T> fmt::Debug for Labeled<T> {
     }
 }
 
+/// Converts a value to a string, like `@base64`. Errors are returned as exceptions.
+pub type FormatFn = Labeled<Arc<Fn(Value) -> Value + Send + Sync>>;
 pub type FilterFn = Labeled<Arc<Fn(&[Filter], Receiver, Sender) + Send + Sync>>;
+/// Runs a custom filter in path mode, like `Filter::run_paths`.
+pub type PathsFn = Labeled<Arc<Fn(&[Filter], &Context, Vec<Value>, Value, &mut FnMut(Vec<Value>, Value) -> bool) -> bool + Send + Sync>>;
+pub type OperatorFn = Labeled<Arc<Fn(Value, Value) -> Value + Send + Sync>>;
+
+/// Parses `code` in the default context and runs it on `input`, returning the outputs as they are shown in the REPL.
+#[cfg(test)]
+pub fn run_with_input(code: &str, input: Value) -> Vec<String> {
+    use builtin;
+    use lang::parser;
+
+    let f = parser::parse(code, builtin::context()).unwrap();
+    Receiver::from_values(builtin::context(), vec![input]).filter(&f).into_iter().map(|v| v.to_string()).collect()
+}
+
+/// Runs `code` on `null`, like `run_with_input`.
+#[cfg(test)]
+pub fn run(code: &str) -> Vec<String> {
+    run_with_input(code, Value::Null)
+}